@@ -0,0 +1,495 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+/// The default size of the read and write buffers.
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 32 * 1024;
+
+/// The sizes of the ciphertext buffers kept between the TLS backend and the
+/// transport.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct BufferSizes {
+    pub(crate) read: usize,
+    pub(crate) write: usize,
+}
+
+impl Default for BufferSizes {
+    fn default() -> BufferSizes {
+        BufferSizes {
+            read: DEFAULT_BUFFER_SIZE,
+            write: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+/// Adapts an async stream to the blocking `Read` and `Write` traits the TLS
+/// backends expect.
+///
+/// The waker of the task driving the TLS session is registered with
+/// `register` for the duration of each poll. Operations on `S` which are not
+/// ready register that waker and report `WouldBlock`, which the backends pass
+/// back up so the poll can return `Pending`. If the backend touches the
+/// stream while no poll is in progress there is no waker to register, and the
+/// operation fails with an error instead.
+///
+/// The backends read and write one TLS record, or even one record header, at
+/// a time. To save system calls, ciphertext is read from `S` in chunks of up
+/// to the configured read buffer size, and records written by the backend are
+/// collected in a write buffer, which is sent by `flush_buf`. Write buffering
+/// is only enabled with `buffer_writes` once the handshake has completed, so
+/// that alerts sent right before a failed handshake drops the transport are
+/// not lost.
+///
+/// Reads never extend past the end of the TLS record being read. Backends
+/// which read ahead into buffers of their own therefore stop at the record
+/// which ends the session, and whatever follows it is left in the read
+/// buffer for `take_inner` to return.
+pub(crate) struct AllowStd<S> {
+    // Only `None` once `take_inner` has been called.
+    inner: Option<S>,
+    waker: Option<Waker>,
+    sizes: BufferSizes,
+    buffer_writes: bool,
+    // Ciphertext read from `inner` which the backend has not consumed yet is
+    // held in `rbuf[rpos..rend]`.
+    rbuf: Vec<u8>,
+    rpos: usize,
+    rend: usize,
+    wbuf: Vec<u8>,
+    eof: bool,
+    // The position in the record stream: the bytes of the current record's
+    // header read so far, and the length of its body left to read.
+    header: [u8; 5],
+    header_len: usize,
+    body_left: usize,
+}
+
+impl<S> AllowStd<S> {
+    /// Creates an adapter for `inner`. `prefix` holds bytes already read from
+    /// `inner`, which are replayed to the backend first.
+    pub(crate) fn new(inner: S, prefix: Vec<u8>, sizes: BufferSizes) -> AllowStd<S> {
+        AllowStd {
+            inner: Some(inner),
+            waker: None,
+            sizes,
+            buffer_writes: false,
+            rpos: 0,
+            rend: prefix.len(),
+            rbuf: prefix,
+            wbuf: Vec::new(),
+            eof: false,
+            header: [0; 5],
+            header_len: 0,
+            body_left: 0,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        self.inner.as_ref().expect(TAKEN)
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut S {
+        self.inner.as_mut().expect(TAKEN)
+    }
+
+    /// Takes the transport out of the adapter, along with the bytes read
+    /// from it which the backend has not consumed.
+    ///
+    /// The adapter must not be used afterwards.
+    pub(crate) fn take_inner(&mut self) -> (S, Vec<u8>) {
+        let inner = self.inner.take().expect(TAKEN);
+        let rest = self.rbuf[self.rpos..self.rend].to_vec();
+        self.rpos = self.rend;
+        (inner, rest)
+    }
+
+    /// Limits a read of `len` bytes to the end of the current record, or of
+    /// its header.
+    fn record_limit(&self, len: usize) -> usize {
+        if self.body_left > 0 {
+            cmp::min(len, self.body_left)
+        } else {
+            cmp::min(len, self.header.len() - self.header_len)
+        }
+    }
+
+    /// Advances the position in the record stream past `data`, which does
+    /// not extend beyond the limit returned by `record_limit`.
+    fn advance_record(&mut self, data: &[u8]) {
+        if self.body_left > 0 {
+            self.body_left -= data.len();
+            return;
+        }
+        self.header[self.header_len..self.header_len + data.len()].copy_from_slice(data);
+        self.header_len += data.len();
+        if self.header_len == self.header.len() {
+            self.body_left = usize::from(u16::from_be_bytes([self.header[3], self.header[4]]));
+            self.header_len = 0;
+        }
+    }
+
+    /// Returns `true` once a read from `S` has returned end of file.
+    pub(crate) fn is_eof(&self) -> bool {
+        self.eof
+    }
+
+    /// Makes `cx`'s waker the one woken when `S` becomes ready, until
+    /// `unregister` is called.
+    pub(crate) fn register(&mut self, cx: &Context<'_>) {
+        self.waker = Some(cx.waker().clone());
+    }
+
+    pub(crate) fn unregister(&mut self) {
+        self.waker = None;
+    }
+
+    /// Starts collecting written records in the write buffer.
+    pub(crate) fn buffer_writes(&mut self) {
+        self.buffer_writes = true;
+    }
+
+    /// Writes the contents of the write buffer to `S`.
+    pub(crate) fn flush_buf(&mut self) -> io::Result<()>
+    where
+        S: AsyncWrite + Unpin,
+    {
+        let mut written = 0;
+        let res = loop {
+            if written == self.wbuf.len() {
+                break Ok(());
+            }
+            let buf = &self.wbuf[written..];
+            match poll_io(&self.waker, &mut self.inner, |cx, s| s.poll_write(cx, buf)) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) => break Err(e),
+            }
+        };
+        self.wbuf.drain(..written);
+        res
+    }
+}
+
+const TAKEN: &str = "transport taken from `AllowStd`";
+
+fn poll_io<S, F, R>(waker: &Option<Waker>, inner: &mut Option<S>, f: F) -> io::Result<R>
+where
+    F: FnOnce(&mut Context<'_>, Pin<&mut S>) -> Poll<io::Result<R>>,
+    S: Unpin,
+{
+    let inner = inner.as_mut().expect(TAKEN);
+    let waker = match *waker {
+        Some(ref waker) => waker,
+        None => {
+            let msg = "TLS stream used outside of a poll";
+            return Err(io::Error::new(io::ErrorKind::Other, msg));
+        }
+    };
+    let mut cx = Context::from_waker(waker);
+    match f(&mut cx, Pin::new(inner)) {
+        Poll::Ready(r) => r,
+        Poll::Pending => Err(io::ErrorKind::WouldBlock.into()),
+    }
+}
+
+impl<S> Read for AllowStd<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.record_limit(buf.len());
+        let buf = &mut buf[..len];
+
+        if self.rpos == self.rend {
+            // Large reads gain nothing from the extra copy.
+            if buf.len() >= self.sizes.read {
+                let n = poll_io(&self.waker, &mut self.inner, |cx, s| s.poll_read(cx, buf))?;
+                self.eof |= n == 0 && !buf.is_empty();
+                self.advance_record(&buf[..n]);
+                return Ok(n);
+            }
+
+            if self.rbuf.len() < self.sizes.read {
+                self.rbuf = vec![0; self.sizes.read];
+            }
+            let rbuf = &mut self.rbuf[..];
+            let n = poll_io(&self.waker, &mut self.inner, |cx, s| s.poll_read(cx, rbuf))?;
+            self.rpos = 0;
+            self.rend = n;
+            self.eof |= n == 0;
+        }
+
+        let n = cmp::min(buf.len(), self.rend - self.rpos);
+        buf[..n].copy_from_slice(&self.rbuf[self.rpos..self.rpos + n]);
+        self.rpos += n;
+        self.advance_record(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<S> Write for AllowStd<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let cap = if self.buffer_writes {
+            self.sizes.write
+        } else {
+            0
+        };
+        if self.wbuf.len() + buf.len() > cap {
+            self.flush_buf()?;
+        }
+        if buf.len() >= cap {
+            return poll_io(&self.waker, &mut self.inner, |cx, s| s.poll_write(cx, buf));
+        }
+        self.wbuf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_buf()?;
+        poll_io(&self.waker, &mut self.inner, |cx, s| s.poll_flush(cx))
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for AllowStd<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AllowStd")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllowStd, BufferSizes};
+
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    use std::io::{self, Read, Write};
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    /// A stream which is never ready.
+    struct Pending;
+
+    impl AsyncRead for Pending {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for Pending {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Pending
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    /// A stream which is always ready, counting the calls made to it.
+    #[derive(Default)]
+    struct Counting {
+        input: Vec<u8>,
+        reads: usize,
+        output: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncRead for Counting {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            self.reads += 1;
+            let n = std::cmp::min(buf.len(), self.input.len());
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input.drain(..n);
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for Counting {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes += 1;
+            self.output.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    struct Noop;
+
+    /// Returns `n` application data records with bodies of `len` bytes.
+    fn records(n: usize, len: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        for _ in 0..n {
+            data.extend_from_slice(&[23, 3, 3]);
+            data.extend_from_slice(&len.to_be_bytes());
+            data.resize(data.len() + usize::from(len), 7);
+        }
+        data
+    }
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    fn outside_poll_is_an_error() {
+        let mut io = AllowStd::new(Pending, Vec::new(), BufferSizes::default());
+        let mut buf = [0; 8];
+
+        let err = io.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        let err = io.write(b"hello").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        let err = io.flush().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn inside_poll_would_block() {
+        let mut io = AllowStd::new(Pending, Vec::new(), BufferSizes::default());
+        let mut buf = [0; 8];
+
+        let waker = Waker::from(Arc::new(Noop));
+        io.register(&Context::from_waker(&waker));
+        let err = io.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        let err = io.write(b"hello").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        io.unregister();
+        let err = io.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn prefix_is_read_first() {
+        let mut io = AllowStd::new(Pending, b"hello".to_vec(), BufferSizes::default());
+        let mut buf = [0; 3];
+
+        assert_eq!(io.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        assert_eq!(io.read(&mut buf).unwrap(), 2);
+        assert_eq!(&buf[..2], b"lo");
+        assert!(io.read(&mut buf).is_err());
+    }
+
+    #[test]
+    fn reads_are_batched() {
+        let mut input = records(8, 123);
+        input.extend(records(1, 1200));
+        let stream = Counting {
+            input,
+            ..Counting::default()
+        };
+        let sizes = BufferSizes {
+            read: 512,
+            write: 512,
+        };
+        let mut io = AllowStd::new(stream, Vec::new(), sizes);
+        let waker = Waker::from(Arc::new(Noop));
+        io.register(&Context::from_waker(&waker));
+
+        // Record headers and bodies are read in small pieces, which are
+        // served from few reads of the transport.
+        for _ in 0..8 {
+            assert_eq!(io.read(&mut [0; 5]).unwrap(), 5);
+            assert_eq!(io.read(&mut [0; 123]).unwrap(), 123);
+        }
+        assert_eq!(io.get_ref().reads, 2);
+
+        // Reads at least as large as the buffer bypass it once it is empty.
+        assert_eq!(io.read(&mut [0; 5]).unwrap(), 5);
+        assert_eq!(io.get_ref().reads, 3);
+        let mut buf = vec![0; 1200];
+        assert_eq!(io.read(&mut buf).unwrap(), 507);
+        assert_eq!(io.read(&mut buf[507..]).unwrap(), 693);
+        assert_eq!(io.get_ref().reads, 4);
+
+        assert!(!io.is_eof());
+        assert_eq!(io.read(&mut buf).unwrap(), 0);
+        assert!(io.is_eof());
+    }
+
+    #[test]
+    fn reads_stop_at_record_boundaries() {
+        let mut input = records(2, 10);
+        input.extend_from_slice(b"plaintext");
+        let stream = Counting {
+            input,
+            ..Counting::default()
+        };
+        let mut io = AllowStd::new(stream, Vec::new(), BufferSizes::default());
+        let waker = Waker::from(Arc::new(Noop));
+        io.register(&Context::from_waker(&waker));
+
+        let mut buf = [0; 64];
+        for &len in &[5, 10, 5, 10] {
+            assert_eq!(io.read(&mut buf).unwrap(), len);
+        }
+        assert_eq!(io.get_ref().reads, 1);
+
+        let (stream, rest) = io.take_inner();
+        assert_eq!(rest, b"plaintext");
+        assert!(stream.input.is_empty());
+    }
+
+    #[test]
+    fn writes_are_batched() {
+        let sizes = BufferSizes {
+            read: 512,
+            write: 512,
+        };
+        let mut io = AllowStd::new(Counting::default(), Vec::new(), sizes);
+        let waker = Waker::from(Arc::new(Noop));
+        io.register(&Context::from_waker(&waker));
+
+        // Writes go straight through during the handshake.
+        io.write_all(&[1; 100]).unwrap();
+        assert_eq!(io.get_ref().writes, 1);
+
+        io.buffer_writes();
+        for _ in 0..10 {
+            io.write_all(&[2; 100]).unwrap();
+        }
+        assert_eq!(io.get_ref().writes, 2);
+        io.flush().unwrap();
+        assert_eq!(io.get_ref().writes, 3);
+        assert_eq!(io.get_ref().output.len(), 1100);
+    }
+}
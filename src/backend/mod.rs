@@ -0,0 +1,447 @@
+//! The TLS implementations which `TlsConnector`, `TlsAcceptor` and
+//! `TlsStream` dispatch to.
+//!
+//! Every backend provides a blocking-style session which is driven over a
+//! `Read + Write` transport returning `WouldBlock` whenever the underlying
+//! async stream is not ready, mirroring the `native-tls` API. The types in
+//! this module are enums with one variant per enabled backend.
+
+#[cfg(feature = "openssl")]
+mod openssl;
+#[cfg(feature = "rustls")]
+mod rustls;
+
+use crate::session::{ClientSessions, SessionCache};
+use crate::{Certificate, ClientAuth, Error, SessionResumption};
+
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub(crate) enum Connector {
+    NativeTls(native_tls::TlsConnector),
+    /// OpenSSL contexts cannot be changed once built, so whether the server's
+    /// certificate is verified, and the session to resume, are set on each
+    /// connection instead.
+    #[cfg(feature = "openssl")]
+    OpenSsl(
+        ::openssl::ssl::SslConnector,
+        bool,
+        Option<self::openssl::ContextCache>,
+    ),
+    /// The session cache is also set as the session storage of the
+    /// configuration.
+    #[cfg(feature = "rustls")]
+    Rustls(Arc<::rustls::ClientConfig>, Option<Arc<SessionCache>>),
+}
+
+#[derive(Clone)]
+pub(crate) enum Acceptor {
+    NativeTls(native_tls::TlsAcceptor),
+    /// OpenSSL contexts cannot be changed once built, so client
+    /// authentication is configured on each connection instead.
+    #[cfg(feature = "openssl")]
+    OpenSsl(
+        ::openssl::ssl::SslAcceptor,
+        Option<std::sync::Arc<self::openssl::ClientAuth>>,
+    ),
+    #[cfg(feature = "rustls")]
+    Rustls(std::sync::Arc<::rustls::ServerConfig>),
+}
+
+/// An established TLS session over `IO`.
+pub(crate) enum Stream<IO> {
+    NativeTls(native_tls::TlsStream<IO>),
+    #[cfg(feature = "openssl")]
+    OpenSsl(::openssl::ssl::SslStream<IO>),
+    #[cfg(feature = "rustls")]
+    Rustls(self::rustls::RustlsStream<IO>),
+}
+
+/// A TLS session over `IO` whose handshake has not completed yet.
+pub(crate) enum MidHandshake<IO> {
+    NativeTls(native_tls::MidHandshakeTlsStream<IO>),
+    #[cfg(feature = "openssl")]
+    OpenSsl(::openssl::ssl::MidHandshakeSslStream<IO>),
+    #[cfg(feature = "rustls")]
+    Rustls(Box<self::rustls::RustlsStream<IO>>),
+}
+
+pub(crate) enum HandshakeError<IO> {
+    /// The transport returned `WouldBlock`; the handshake can be resumed
+    /// with `MidHandshake::handshake` once it is ready again.
+    WouldBlock(MidHandshake<IO>),
+    Failure(Error),
+}
+
+impl Connector {
+    /// Connects to `domain`, resuming a session cached for it and `addr`, if
+    /// any.
+    #[cfg_attr(
+        not(any(feature = "openssl", feature = "rustls")),
+        allow(unused_variables)
+    )]
+    pub(crate) fn connect<IO>(
+        &self,
+        domain: &str,
+        addr: Option<SocketAddr>,
+        io: IO,
+    ) -> Result<Stream<IO>, HandshakeError<IO>>
+    where
+        IO: Read + Write,
+    {
+        match *self {
+            Connector::NativeTls(ref c) => native_tls_result(c.connect(domain, io)),
+            #[cfg(feature = "openssl")]
+            Connector::OpenSsl(ref c, verify, ref cache) => {
+                let key = crate::session::key(domain, addr);
+                let cache = cache.as_ref().map(|cache| (cache, &key[..]));
+                self::openssl::connect(c, verify, cache, domain, io)
+            }
+            #[cfg(feature = "rustls")]
+            Connector::Rustls(ref config, _) => self::rustls::connect(config, addr, domain, io),
+        }
+    }
+
+    /// Returns a copy of this connector which accepts any certificate
+    /// presented by the server, for any domain.
+    ///
+    /// The copy caches sessions in an empty cache of the same size, so that
+    /// sessions established without verification are never resumed by a
+    /// connector which verifies the server. rustls configurations without a
+    /// cache do not resume sessions at all for the same reason.
+    pub(crate) fn without_verification(&self) -> Result<Connector, Error> {
+        match *self {
+            Connector::NativeTls(_) => Err(unsupported("custom certificate verification")),
+            #[cfg(feature = "openssl")]
+            Connector::OpenSsl(ref c, _, ref cache) => {
+                let cache = cache.as_ref().map(|cache| {
+                    let cache = SessionCache::new(cache.cache().capacity());
+                    self::openssl::ContextCache::new(c, Arc::new(cache))
+                });
+                Ok(Connector::OpenSsl(c.clone(), false, cache))
+            }
+            #[cfg(feature = "rustls")]
+            Connector::Rustls(ref config, ref cache) => {
+                let cache = cache
+                    .as_ref()
+                    .map(|cache| Arc::new(SessionCache::new(cache.capacity())));
+                let config = self::rustls::without_verification(config);
+                Ok(Connector::Rustls(
+                    self::rustls::with_session_cache(&config, cache.as_ref()),
+                    cache,
+                ))
+            }
+        }
+    }
+
+    /// Returns a copy of this connector which caches sessions in `cache`, or
+    /// does not resume sessions if it is `None`.
+    pub(crate) fn with_session_cache(
+        &self,
+        cache: Option<Arc<SessionCache>>,
+    ) -> Result<Connector, Error> {
+        match *self {
+            Connector::NativeTls(_) if cache.is_none() => Ok(self.clone()),
+            Connector::NativeTls(_) => Err(unsupported("session caching")),
+            #[cfg(feature = "openssl")]
+            Connector::OpenSsl(ref c, verify, _) => {
+                let cache = cache.map(|cache| self::openssl::ContextCache::new(c, cache));
+                Ok(Connector::OpenSsl(c.clone(), verify, cache))
+            }
+            #[cfg(feature = "rustls")]
+            Connector::Rustls(ref config, _) => Ok(Connector::Rustls(
+                self::rustls::with_session_cache(config, cache.as_ref()),
+                cache,
+            )),
+        }
+    }
+
+    pub(crate) fn session_cache(&self) -> Option<&Arc<SessionCache>> {
+        match *self {
+            Connector::NativeTls(_) => None,
+            #[cfg(feature = "openssl")]
+            Connector::OpenSsl(_, _, ref cache) => cache.as_ref().map(|cache| cache.cache()),
+            #[cfg(feature = "rustls")]
+            Connector::Rustls(_, ref cache) => cache.as_ref(),
+        }
+    }
+}
+
+impl Acceptor {
+    pub(crate) fn accept<IO>(&self, io: IO) -> Result<Stream<IO>, HandshakeError<IO>>
+    where
+        IO: Read + Write,
+    {
+        match *self {
+            Acceptor::NativeTls(ref a) => native_tls_result(a.accept(io)),
+            #[cfg(feature = "openssl")]
+            Acceptor::OpenSsl(ref a, ref auth) => self::openssl::accept(a, auth.as_deref(), io),
+            #[cfg(feature = "rustls")]
+            Acceptor::Rustls(ref config) => self::rustls::accept(config, io),
+        }
+    }
+
+    /// Returns a copy of this acceptor which authenticates clients as set by
+    /// `auth`.
+    pub(crate) fn with_client_auth(&self, auth: &ClientAuth) -> Result<Acceptor, Error> {
+        match *self {
+            Acceptor::NativeTls(_) if auth.roots().is_none() => Ok(self.clone()),
+            Acceptor::NativeTls(_) => Err(unsupported("client authentication")),
+            #[cfg(feature = "openssl")]
+            Acceptor::OpenSsl(ref a, _) => {
+                let auth = self::openssl::client_auth(auth)?;
+                Ok(Acceptor::OpenSsl(a.clone(), auth.map(std::sync::Arc::new)))
+            }
+            #[cfg(feature = "rustls")]
+            Acceptor::Rustls(ref config) => Ok(Acceptor::Rustls(self::rustls::with_client_auth(
+                config, auth,
+            )?)),
+        }
+    }
+
+    /// Returns a copy of this acceptor which resumes sessions as set by
+    /// `resumption`.
+    #[cfg_attr(not(feature = "rustls"), allow(unused_variables))]
+    pub(crate) fn with_session_resumption(
+        &self,
+        resumption: &SessionResumption,
+    ) -> Result<Acceptor, Error> {
+        match *self {
+            Acceptor::NativeTls(_) => Err(unsupported("configuring session resumption")),
+            #[cfg(feature = "openssl")]
+            Acceptor::OpenSsl(..) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "OpenSSL acceptors cannot be changed once built, \
+                 configure session resumption on the `SslAcceptorBuilder` instead",
+            )
+            .into()),
+            #[cfg(feature = "rustls")]
+            Acceptor::Rustls(ref config) => Ok(Acceptor::Rustls(
+                self::rustls::with_session_resumption(config, resumption),
+            )),
+        }
+    }
+}
+
+/// Returns the error reported when a feature native-tls lacks is requested
+/// from a native-tls connector or acceptor.
+fn unsupported(feature: &str) -> Error {
+    let msg = format!(
+        "native-tls does not support {}, use the openssl or rustls backend instead",
+        feature
+    );
+    io::Error::new(io::ErrorKind::Other, msg).into()
+}
+
+fn native_tls_result<IO>(
+    res: Result<native_tls::TlsStream<IO>, native_tls::HandshakeError<IO>>,
+) -> Result<Stream<IO>, HandshakeError<IO>> {
+    match res {
+        Ok(s) => Ok(Stream::NativeTls(s)),
+        Err(native_tls::HandshakeError::WouldBlock(s)) => {
+            Err(HandshakeError::WouldBlock(MidHandshake::NativeTls(s)))
+        }
+        Err(native_tls::HandshakeError::Failure(e)) => Err(HandshakeError::Failure(e.into())),
+    }
+}
+
+impl<IO: Read + Write> MidHandshake<IO> {
+    pub(crate) fn handshake(self) -> Result<Stream<IO>, HandshakeError<IO>> {
+        match self {
+            MidHandshake::NativeTls(s) => native_tls_result(s.handshake()),
+            #[cfg(feature = "openssl")]
+            MidHandshake::OpenSsl(s) => self::openssl::handshake_result(s.handshake()),
+            #[cfg(feature = "rustls")]
+            MidHandshake::Rustls(s) => (*s).handshake(),
+        }
+    }
+}
+
+impl<IO> MidHandshake<IO> {
+    pub(crate) fn get_mut(&mut self) -> &mut IO {
+        match *self {
+            MidHandshake::NativeTls(ref mut s) => s.get_mut(),
+            #[cfg(feature = "openssl")]
+            MidHandshake::OpenSsl(ref mut s) => s.get_mut(),
+            #[cfg(feature = "rustls")]
+            MidHandshake::Rustls(ref mut s) => s.get_mut(),
+        }
+    }
+}
+
+impl<IO> Stream<IO> {
+    pub(crate) fn get_ref(&self) -> &IO {
+        match *self {
+            Stream::NativeTls(ref s) => s.get_ref(),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref s) => s.get_ref(),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref s) => s.get_ref(),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut IO {
+        match *self {
+            Stream::NativeTls(ref mut s) => s.get_mut(),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref mut s) => s.get_mut(),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref mut s) => s.get_mut(),
+        }
+    }
+
+    /// Returns the SNI host name received by a server, if the backend keeps
+    /// track of it.
+    pub(crate) fn server_name(&self) -> Option<&str> {
+        match *self {
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref s) => s.server_name(),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the handshake resumed an earlier session, if the
+    /// backend tells.
+    pub(crate) fn session_resumed(&self) -> bool {
+        match *self {
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref s) => s.ssl().session_reused(),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref s) => s.session_resumed(),
+            _ => false,
+        }
+    }
+
+    /// Caches the session of a client connection, if it has been replaced
+    /// since it was last seen.
+    ///
+    /// rustls caches its sessions through its configuration instead.
+    #[cfg_attr(not(feature = "openssl"), allow(unused_variables))]
+    pub(crate) fn save_session(&self, sessions: &mut ClientSessions) {
+        match *self {
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref s) => self::openssl::save_session(s, sessions),
+            _ => {}
+        }
+    }
+
+    #[cfg(feature = "openssl")]
+    pub(crate) fn ssl(&self) -> Option<&::openssl::ssl::SslRef> {
+        match *self {
+            Stream::OpenSsl(ref s) => Some(s.ssl()),
+            _ => None,
+        }
+    }
+}
+
+impl<IO: Read + Write> Stream<IO> {
+    /// Returns whether the peer has closed the session with a close_notify
+    /// alert, or `None` if the backend does not tell.
+    pub(crate) fn close_notify_received(&mut self) -> Option<bool> {
+        match *self {
+            Stream::NativeTls(_) => None,
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref mut s) => Some(self::openssl::close_notify_received(s)),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref s) => Some(s.close_notify_received()),
+        }
+    }
+
+    pub(crate) fn peer_certificate(&self) -> Result<Option<Certificate>, Error> {
+        match *self {
+            Stream::NativeTls(ref s) => match s.peer_certificate()? {
+                Some(cert) => Ok(Some(Certificate::from_der(cert.to_der()?))),
+                None => Ok(None),
+            },
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref s) => Ok(self::openssl::peer_certificate(s)?),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref s) => Ok(s.peer_certificates().and_then(|c| c.into_iter().next())),
+        }
+    }
+
+    /// Returns the certificate a client authenticated with, on the server
+    /// side of a session.
+    pub(crate) fn client_certificate(&self) -> Result<Option<Certificate>, Error> {
+        match *self {
+            // native-tls servers never ask for client certificates.
+            Stream::NativeTls(_) => Ok(None),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref s) if s.ssl().is_server() => self.peer_certificate(),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref s) if s.is_server() => self.peer_certificate(),
+            #[cfg(any(feature = "openssl", feature = "rustls"))]
+            _ => Ok(None),
+        }
+    }
+
+    pub(crate) fn peer_certificate_chain(&self) -> Result<Option<Vec<Certificate>>, Error> {
+        match *self {
+            // native-tls only exposes the leaf certificate.
+            Stream::NativeTls(_) => Ok(None),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref s) => Ok(self::openssl::peer_certificate_chain(s)?),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref s) => Ok(s.peer_certificates()),
+        }
+    }
+
+    pub(crate) fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>, Error> {
+        match *self {
+            #[cfg(feature = "alpn")]
+            Stream::NativeTls(ref s) => Ok(s.negotiated_alpn()?),
+            #[cfg(not(feature = "alpn"))]
+            Stream::NativeTls(_) => Ok(None),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref s) => Ok(s.ssl().selected_alpn_protocol().map(|p| p.to_vec())),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref s) => Ok(s.negotiated_alpn()),
+        }
+    }
+
+    pub(crate) fn shutdown(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::NativeTls(ref mut s) => s.shutdown(),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref mut s) => self::openssl::shutdown(s),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref mut s) => s.shutdown(),
+        }
+    }
+}
+
+impl<IO: Read + Write> Read for Stream<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::NativeTls(ref mut s) => s.read(buf),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref mut s) => s.read(buf),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl<IO: Read + Write> Write for Stream<IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::NativeTls(ref mut s) => s.write(buf),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref mut s) => s.write(buf),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::NativeTls(ref mut s) => s.flush(),
+            #[cfg(feature = "openssl")]
+            Stream::OpenSsl(ref mut s) => s.flush(),
+            #[cfg(feature = "rustls")]
+            Stream::Rustls(ref mut s) => s.flush(),
+        }
+    }
+}
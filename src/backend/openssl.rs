@@ -0,0 +1,241 @@
+use super::{HandshakeError, MidHandshake, Stream};
+use crate::session::{ClientSessions, SessionCache};
+use crate::{Certificate, Error};
+
+use openssl::error::ErrorStack;
+use openssl::ssl::{
+    self, ErrorCode, ShutdownState, Ssl, SslAcceptor, SslConnector, SslContext, SslSession,
+    SslStream, SslVerifyMode,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509Ref, X509};
+
+use std::io::{self, Read, Write};
+use std::ptr;
+use std::sync::Arc;
+
+/// A session cache, together with the context of the connector whose
+/// sessions it holds.
+///
+/// OpenSSL only resumes sessions with the context they were established
+/// with, or a compatible one. A cache is created for a single connector, and
+/// only shared with its clones, so all its sessions come from that context.
+#[derive(Clone)]
+pub(crate) struct ContextCache {
+    context: SslContext,
+    cache: Arc<SessionCache>,
+}
+
+impl ContextCache {
+    pub(super) fn new(connector: &SslConnector, cache: Arc<SessionCache>) -> ContextCache {
+        ContextCache {
+            context: connector.context().to_owned(),
+            cache,
+        }
+    }
+
+    pub(super) fn cache(&self) -> &Arc<SessionCache> {
+        &self.cache
+    }
+}
+
+/// Connects to `domain`, resuming the session cached under `key` in `cache`,
+/// if any.
+pub(super) fn connect<IO>(
+    connector: &SslConnector,
+    verify: bool,
+    cache: Option<(&ContextCache, &[u8])>,
+    domain: &str,
+    io: IO,
+) -> Result<Stream<IO>, HandshakeError<IO>>
+where
+    IO: Read + Write,
+{
+    if verify && cache.is_none() {
+        return handshake_result(connector.connect(domain, io));
+    }
+    match connect_ssl(connector, verify, cache, domain) {
+        Ok(ssl) => handshake_result(ssl.connect(io)),
+        Err(e) => Err(HandshakeError::Failure(ssl::Error::from(e).into())),
+    }
+}
+
+/// Creates a session with `domain` which verifies the server's certificate
+/// if `verify` is set, and offers to resume the session cached under `key`
+/// in `cache`.
+fn connect_ssl(
+    connector: &SslConnector,
+    verify: bool,
+    cache: Option<(&ContextCache, &[u8])>,
+    domain: &str,
+) -> Result<Ssl, ErrorStack> {
+    let mut config = connector.configure()?;
+    if !verify {
+        config = config.verify_hostname(false);
+        config.set_verify(SslVerifyMode::NONE);
+    }
+    let mut ssl = config.into_ssl(domain)?;
+    let session = match cache {
+        Some((cache, key)) if ptr::eq(&*cache.context, connector.context()) => {
+            cache.cache.lookup(key)
+        }
+        _ => None,
+    };
+    if let Some(session) = session {
+        let session = SslSession::from_der(&session)?;
+        // `set_session` requires the session to have been established with
+        // a context compatible with the one of `ssl`. The cache only holds
+        // sessions established with its context, which was just checked to
+        // be the one `ssl` was created from.
+        unsafe { ssl.set_session(&session)? };
+    }
+    Ok(ssl)
+}
+
+/// Caches the session of `s`, once it has been replaced by one the server
+/// allows to resume.
+pub(super) fn save_session<IO>(s: &SslStream<IO>, sessions: &mut ClientSessions) {
+    let session = match s.ssl().session() {
+        Some(session) => session,
+        None => return,
+    };
+    // Sessions which can be resumed have an ID, either picked by the server
+    // or derived from a ticket. With TLS 1.3 the server only sends those
+    // after the handshake, each of which replaces the session.
+    let id = session.id();
+    if id.is_empty() || sessions.seen == id {
+        return;
+    }
+    if let Ok(der) = session.to_der() {
+        sessions.seen = id.to_vec();
+        sessions.cache.insert(sessions.key.clone(), der);
+    }
+}
+
+/// The client authentication settings applied to the connections of an
+/// `SslAcceptor`.
+pub(crate) struct ClientAuth {
+    mode: SslVerifyMode,
+    roots: Vec<X509>,
+}
+
+pub(super) fn client_auth(auth: &crate::ClientAuth) -> Result<Option<ClientAuth>, Error> {
+    let roots = match auth.roots() {
+        Some(roots) => roots,
+        None => return Ok(None),
+    };
+    let mut mode = SslVerifyMode::PEER;
+    if let crate::ClientAuth::Required(_) = *auth {
+        mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+    }
+    let roots = roots
+        .iter()
+        .map(|root| X509::from_der(root.der()))
+        .collect::<Result<_, _>>()?;
+    Ok(Some(ClientAuth { mode, roots }))
+}
+
+pub(super) fn accept<IO>(
+    acceptor: &SslAcceptor,
+    auth: Option<&ClientAuth>,
+    io: IO,
+) -> Result<Stream<IO>, HandshakeError<IO>>
+where
+    IO: Read + Write,
+{
+    let auth = match auth {
+        Some(auth) => auth,
+        None => return handshake_result(acceptor.accept(io)),
+    };
+    match client_auth_ssl(acceptor, auth) {
+        Ok(ssl) => handshake_result(ssl.accept(io)),
+        Err(e) => Err(HandshakeError::Failure(ssl::Error::from(e).into())),
+    }
+}
+
+/// Creates a session which verifies client certificates against the roots
+/// of `auth`, and names them in its certificate request.
+fn client_auth_ssl(acceptor: &SslAcceptor, auth: &ClientAuth) -> Result<Ssl, ErrorStack> {
+    let mut ssl = Ssl::new(acceptor.context())?;
+    ssl.set_verify(auth.mode);
+
+    let mut store = X509StoreBuilder::new()?;
+    let mut names = Stack::new()?;
+    for root in &auth.roots {
+        store.add_cert(root.clone())?;
+        names.push(root.subject_name().to_owned()?)?;
+    }
+    ssl.set_verify_cert_store(store.build())?;
+    ssl.set_client_ca_list(names);
+    Ok(ssl)
+}
+
+pub(super) fn handshake_result<IO>(
+    res: Result<SslStream<IO>, ssl::HandshakeError<IO>>,
+) -> Result<Stream<IO>, HandshakeError<IO>> {
+    match res {
+        Ok(s) => Ok(Stream::OpenSsl(s)),
+        Err(ssl::HandshakeError::WouldBlock(s)) => {
+            Err(HandshakeError::WouldBlock(MidHandshake::OpenSsl(s)))
+        }
+        Err(ssl::HandshakeError::Failure(s)) => {
+            let verify = s.ssl().verify_result();
+            Err(HandshakeError::Failure(Error::openssl_handshake(
+                s.into_error(),
+                verify,
+            )))
+        }
+        Err(ssl::HandshakeError::SetupFailure(e)) => {
+            Err(HandshakeError::Failure(ssl::Error::from(e).into()))
+        }
+    }
+}
+
+pub(super) fn peer_certificate<IO>(s: &SslStream<IO>) -> Result<Option<Certificate>, ErrorStack> {
+    match s.ssl().peer_certificate() {
+        Some(cert) => Ok(Some(certificate(&cert)?)),
+        None => Ok(None),
+    }
+}
+
+pub(super) fn peer_certificate_chain<IO>(
+    s: &SslStream<IO>,
+) -> Result<Option<Vec<Certificate>>, ErrorStack> {
+    let leaf = match peer_certificate(s)? {
+        Some(leaf) => leaf,
+        None => return Ok(None),
+    };
+
+    let mut chain = vec![];
+    if let Some(certs) = s.ssl().peer_cert_chain() {
+        for cert in certs {
+            chain.push(certificate(cert)?);
+        }
+    }
+
+    // OpenSSL includes the leaf in the chain on the client side, but not on
+    // the server side.
+    if chain.first() != Some(&leaf) {
+        chain.insert(0, leaf);
+    }
+    Ok(Some(chain))
+}
+
+fn certificate(cert: &X509Ref) -> Result<Certificate, ErrorStack> {
+    Ok(Certificate::from_der(cert.to_der()?))
+}
+
+pub(super) fn close_notify_received<IO: Read + Write>(s: &mut SslStream<IO>) -> bool {
+    s.get_shutdown().contains(ShutdownState::RECEIVED)
+}
+
+pub(super) fn shutdown<IO: Read + Write>(s: &mut SslStream<IO>) -> io::Result<()> {
+    match s.shutdown() {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.code() == ErrorCode::ZERO_RETURN => Ok(()),
+        Err(e) => Err(e
+            .into_io_error()
+            .unwrap_or_else(|e| io::Error::new(io::ErrorKind::Other, e))),
+    }
+}
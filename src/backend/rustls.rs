@@ -0,0 +1,637 @@
+use super::{HandshakeError, MidHandshake, Stream};
+use crate::client_hello::Reader;
+use crate::session::SessionCache;
+use crate::{Certificate, ClientAuth, Error, SessionResumption, TicketKeys};
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientConfig,
+    ClientSession, NoClientAuth, NoClientSessionStorage, NoServerSessionStorage, ProducesTickets,
+    RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, ServerSession,
+    ServerSessionMemoryCache, Session, StoresClientSessions, TLSError,
+};
+use webpki::DNSNameRef;
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// A rustls session together with the transport it is driven over.
+///
+/// rustls is a sans-I/O library, so unlike `native-tls` it does not own the
+/// transport. This type pairs the two and implements the read, write and
+/// handshake loops on top of `Session::read_tls` and `Session::write_tls`.
+pub(crate) struct RustlsStream<IO> {
+    session: Box<Sess>,
+    io: IO,
+    eof: bool,
+    close_notify_sent: bool,
+    close_notify_received: bool,
+    /// The hellos of a client handshake, until they tell whether the server
+    /// resumed a session.
+    hellos: Option<Box<Hellos>>,
+    resumed: bool,
+}
+
+enum Sess {
+    /// A client session, and the address of the server it is connected to if
+    /// known, which its sessions are cached under.
+    Client(ClientSession, Option<SocketAddr>),
+    Server(ServerSession),
+}
+
+/// The bytes exchanged at the start of a client handshake.
+///
+/// rustls does not report whether a client resumed a session, so this is
+/// told from the ServerHello instead.
+#[derive(Default)]
+struct Hellos {
+    client: Vec<u8>,
+    server: Vec<u8>,
+}
+
+/// A transport which records the bytes read from or written to it.
+struct Recording<'a, IO> {
+    io: &'a mut IO,
+    buf: &'a mut Vec<u8>,
+}
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_TYPE_SERVER_HELLO: u8 = 2;
+const EXTENSION_PRE_SHARED_KEY: u16 = 41;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+
+/// The random of a ServerHello which is a TLS 1.3 HelloRetryRequest.
+const HELLO_RETRY_REQUEST: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91,
+    0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+/// Connects to `domain`, caching sessions under keys which include the
+/// server's address `addr`, if known, when `config` stores them in a
+/// `SessionCache`.
+pub(super) fn connect<IO>(
+    config: &Arc<ClientConfig>,
+    addr: Option<SocketAddr>,
+    domain: &str,
+    io: IO,
+) -> Result<Stream<IO>, HandshakeError<IO>>
+where
+    IO: Read + Write,
+{
+    let name = match DNSNameRef::try_from_ascii_str(domain) {
+        Ok(name) => name,
+        Err(e) => {
+            let e = io::Error::new(io::ErrorKind::InvalidInput, e);
+            return Err(HandshakeError::Failure(e.into()));
+        }
+    };
+    let session = with_server_addr(addr, || ClientSession::new(config, name));
+    RustlsStream::new(Sess::Client(session, addr), io).handshake()
+}
+
+thread_local! {
+    /// The address of the server that the client session being driven on
+    /// this thread is connected to, if known.
+    static SERVER_ADDR: Cell<Option<SocketAddr>> = const { Cell::new(None) };
+}
+
+/// Calls `f` with `addr` as the server address which `CachedSessions`
+/// includes in its keys.
+///
+/// rustls only tells the session storage of a configuration the server's
+/// name, so the address is passed alongside while rustls may access the
+/// storage: when a session is created, and when it processes packets.
+fn with_server_addr<T>(addr: Option<SocketAddr>, f: impl FnOnce() -> T) -> T {
+    SERVER_ADDR.with(|cell| {
+        let prev = cell.replace(addr);
+        let ret = f();
+        cell.set(prev);
+        ret
+    })
+}
+
+/// Stores the sessions of rustls clients in a `SessionCache`, under the keys
+/// picked by rustls, which name the server, prefixed with the server's
+/// address if known.
+struct CachedSessions {
+    cache: Arc<SessionCache>,
+}
+
+impl CachedSessions {
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed = crate::session::key("", SERVER_ADDR.with(Cell::get));
+        prefixed.extend_from_slice(key);
+        prefixed
+    }
+}
+
+impl StoresClientSessions for CachedSessions {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.cache.insert(self.key(&key), value);
+        true
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        // rustls also stores which key exchange group the server picked,
+        // which is not a session.
+        if key.starts_with(b"session") {
+            self.cache.lookup(&self.key(key))
+        } else {
+            self.cache.get(&self.key(key))
+        }
+    }
+}
+
+/// Returns a copy of `config` which stores sessions in `cache`, or does not
+/// resume sessions if `cache` is `None`.
+pub(super) fn with_session_cache(
+    config: &Arc<ClientConfig>,
+    cache: Option<&Arc<SessionCache>>,
+) -> Arc<ClientConfig> {
+    let mut config = (**config).clone();
+    match cache {
+        Some(cache) => config.set_persistence(Arc::new(CachedSessions {
+            cache: cache.clone(),
+        })),
+        None => config.set_persistence(Arc::new(NoClientSessionStorage {})),
+    }
+    Arc::new(config)
+}
+
+pub(super) fn accept<IO>(
+    config: &Arc<ServerConfig>,
+    io: IO,
+) -> Result<Stream<IO>, HandshakeError<IO>>
+where
+    IO: Read + Write,
+{
+    RustlsStream::new(Sess::Server(ServerSession::new(config)), io).handshake()
+}
+
+/// Accepts any certificate chain, leaving it to a `CertificateVerifier` to
+/// check it once the handshake has completed.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _roots: &RootCertStore,
+        _presented_certs: &[rustls::Certificate],
+        _dns_name: DNSNameRef<'_>,
+        _ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Returns a copy of `config` which accepts any certificate presented by the
+/// server.
+pub(super) fn without_verification(config: &Arc<ClientConfig>) -> Arc<ClientConfig> {
+    let mut config = (**config).clone();
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(NoVerification));
+    Arc::new(config)
+}
+
+/// Returns a copy of `config` which authenticates clients as set by `auth`.
+pub(super) fn with_client_auth(
+    config: &Arc<ServerConfig>,
+    auth: &ClientAuth,
+) -> Result<Arc<ServerConfig>, Error> {
+    let mut roots = RootCertStore::empty();
+    for root in auth.roots().unwrap_or(&[]) {
+        let root = rustls::Certificate(root.der().to_vec());
+        roots
+            .add(&root)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    let verifier = match *auth {
+        ClientAuth::None => NoClientAuth::new(),
+        ClientAuth::Optional(_) => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+        ClientAuth::Required(_) => AllowAnyAuthenticatedClient::new(roots),
+    };
+    let mut config = (**config).clone();
+    config.set_client_certificate_verifier(verifier);
+    Ok(Arc::new(config))
+}
+
+/// Returns a copy of `config` which resumes sessions as set by
+/// `resumption`.
+pub(super) fn with_session_resumption(
+    config: &Arc<ServerConfig>,
+    resumption: &SessionResumption,
+) -> Arc<ServerConfig> {
+    let mut config = (**config).clone();
+    match *resumption {
+        SessionResumption::Disabled | SessionResumption::Cache(0) => {
+            config.session_storage = Arc::new(NoServerSessionStorage {});
+            config.ticketer = Arc::new(NoTickets);
+        }
+        SessionResumption::Cache(size) => {
+            config.session_storage = ServerSessionMemoryCache::new(size);
+            config.ticketer = Arc::new(NoTickets);
+        }
+        SessionResumption::Tickets(ref keys) => {
+            config.session_storage = Arc::new(NoServerSessionStorage {});
+            config.ticketer = Arc::new(Ticketer(keys.clone()));
+        }
+    }
+    Arc::new(config)
+}
+
+/// Issues no tickets, so that sessions are only resumed from the session
+/// storage, if any.
+struct NoTickets;
+
+impl ProducesTickets for NoTickets {
+    fn enabled(&self) -> bool {
+        false
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        0
+    }
+
+    fn encrypt(&self, _plain: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn decrypt(&self, _cipher: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Encrypts tickets with ChaCha20-Poly1305 under the current ticket key.
+///
+/// Tickets consist of the name of the key, a random nonce and the sealed
+/// session, with the name as additional data.
+struct Ticketer(TicketKeys);
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn get_lifetime(&self) -> u32 {
+        self.0.lifetime().as_secs().min(u64::from(u32::MAX)) as u32
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let key = self.0.current();
+        let mut nonce = [0; NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        aead_key(&key.key)?
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key.name),
+                &mut sealed,
+            )
+            .ok()?;
+
+        let mut ticket = Vec::with_capacity(key.name.len() + nonce.len() + sealed.len());
+        ticket.extend_from_slice(&key.name);
+        ticket.extend_from_slice(&nonce);
+        ticket.extend_from_slice(&sealed);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        if cipher.len() < 16 + NONCE_LEN {
+            return None;
+        }
+        let (name, rest) = cipher.split_at(16);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let key = self.0.get(name)?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut sealed = sealed.to_vec();
+        let plain = aead_key(&key.key)?
+            .open_in_place(nonce, Aad::from(key.name), &mut sealed)
+            .ok()?;
+        Some(plain.to_vec())
+    }
+}
+
+fn aead_key(key: &[u8]) -> Option<LessSafeKey> {
+    UnboundKey::new(&CHACHA20_POLY1305, key)
+        .ok()
+        .map(LessSafeKey::new)
+}
+
+impl<IO: Read> Read for Recording<'_, IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.io.read(buf)?;
+        self.buf.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<IO: Write> Write for Recording<'_, IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.io.write(buf)?;
+        self.buf.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.io.flush()
+    }
+}
+
+/// Splits the next complete record off `records`, returning its content type
+/// and fragment.
+fn next_record<'a>(records: &mut &'a [u8]) -> Option<(u8, &'a [u8])> {
+    let mut r = Reader(records);
+    let typ = r.u8()?;
+    let _version = r.u16()?;
+    let fragment = r.vec16()?.0;
+    *records = r.0;
+    Some((typ, fragment))
+}
+
+/// Returns whether the server resumed the session offered by the client,
+/// given the bytes each of them sent so far, or `None` until the ServerHello
+/// has been received.
+///
+/// A TLS 1.3 server accepts the offered session with the pre_shared_key
+/// extension, while a TLS 1.2 server echoes the ID of the offered session.
+/// Both hellos are assumed to start their records, as they are the first
+/// handshake messages.
+fn server_hello_resumed(client: &[u8], server: &[u8]) -> Option<bool> {
+    let mut records = server;
+    while let Some((typ, fragment)) = next_record(&mut records) {
+        if typ != CONTENT_TYPE_HANDSHAKE {
+            continue;
+        }
+        let mut r = Reader(fragment);
+        if r.u8() != Some(HANDSHAKE_TYPE_SERVER_HELLO) {
+            continue;
+        }
+        let _len = r.bytes(3)?;
+        let _version = r.u16()?;
+        if r.bytes(32)? == HELLO_RETRY_REQUEST {
+            // The actual ServerHello follows the client's second ClientHello.
+            continue;
+        }
+        let session_id = r.vec8()?.0;
+        let _cipher_suite = r.u16()?;
+        let _compression_method = r.u8()?;
+
+        let (mut tls13, mut psk) = (false, false);
+        if !r.is_empty() {
+            let mut extensions = r.vec16()?;
+            while !extensions.is_empty() {
+                match extensions.u16()? {
+                    EXTENSION_SUPPORTED_VERSIONS => tls13 = true,
+                    EXTENSION_PRE_SHARED_KEY => psk = true,
+                    _ => {}
+                }
+                extensions.vec16()?;
+            }
+        }
+        if tls13 {
+            return Some(psk);
+        }
+        return Some(!session_id.is_empty() && client_session_id(client) == Some(session_id));
+    }
+    None
+}
+
+/// Returns the session ID in the ClientHello starting `client`.
+fn client_session_id(client: &[u8]) -> Option<&[u8]> {
+    let mut records = client;
+    let (_, fragment) = next_record(&mut records)?;
+    let mut r = Reader(fragment);
+    if r.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return None;
+    }
+    let _len = r.bytes(3)?;
+    let _version = r.u16()?;
+    let _random = r.bytes(32)?;
+    Some(r.vec8()?.0)
+}
+
+impl Sess {
+    fn get(&self) -> &dyn Session {
+        match *self {
+            Sess::Client(ref s, _) => s,
+            Sess::Server(ref s) => s,
+        }
+    }
+
+    fn get_mut(&mut self) -> &mut dyn Session {
+        match *self {
+            Sess::Client(ref mut s, _) => s,
+            Sess::Server(ref mut s) => s,
+        }
+    }
+}
+
+impl<IO> RustlsStream<IO> {
+    fn new(session: Sess, io: IO) -> RustlsStream<IO> {
+        let hellos = match session {
+            Sess::Client(..) => Some(Box::default()),
+            Sess::Server(_) => None,
+        };
+        RustlsStream {
+            session: Box::new(session),
+            io,
+            eof: false,
+            close_notify_sent: false,
+            close_notify_received: false,
+            hellos,
+            resumed: false,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &IO {
+        &self.io
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut IO {
+        &mut self.io
+    }
+
+    pub(crate) fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+        let certs = self.session.get().get_peer_certificates()?;
+        Some(
+            certs
+                .into_iter()
+                .map(|c| Certificate::from_der(c.0))
+                .collect(),
+        )
+    }
+
+    pub(crate) fn negotiated_alpn(&self) -> Option<Vec<u8>> {
+        self.session.get().get_alpn_protocol().map(|p| p.to_vec())
+    }
+
+    pub(crate) fn close_notify_received(&self) -> bool {
+        self.close_notify_received
+    }
+
+    pub(crate) fn is_server(&self) -> bool {
+        matches!(*self.session, Sess::Server(_))
+    }
+
+    pub(crate) fn server_name(&self) -> Option<&str> {
+        match *self.session {
+            Sess::Client(..) => None,
+            Sess::Server(ref s) => s.get_sni_hostname(),
+        }
+    }
+
+    /// Returns whether the handshake resumed a session. rustls servers only
+    /// report this for TLS 1.3 sessions.
+    pub(crate) fn session_resumed(&self) -> bool {
+        match *self.session {
+            Sess::Client(..) => self.resumed,
+            Sess::Server(ref s) => s.received_resumption_data().is_some(),
+        }
+    }
+}
+
+impl<IO: Read + Write> RustlsStream<IO> {
+    pub(super) fn handshake(mut self) -> Result<Stream<IO>, HandshakeError<IO>> {
+        match self.complete_handshake() {
+            Ok(()) => Ok(Stream::Rustls(self)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Err(HandshakeError::WouldBlock(
+                MidHandshake::Rustls(Box::new(self)),
+            )),
+            Err(e) => Err(HandshakeError::Failure(Error::from(e))),
+        }
+    }
+
+    fn complete_handshake(&mut self) -> io::Result<()> {
+        while self.session.get().is_handshaking() {
+            if self.session.get().wants_write() {
+                self.write_tls()?;
+            } else {
+                let n = match self.hellos {
+                    Some(ref mut hellos) => self.session.get_mut().read_tls(&mut Recording {
+                        io: &mut self.io,
+                        buf: &mut hellos.server,
+                    })?,
+                    None => self.session.get_mut().read_tls(&mut self.io)?,
+                };
+                if n == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                self.process_new_packets()?;
+
+                if let Some(ref hellos) = self.hellos {
+                    if let Some(resumed) = server_hello_resumed(&hellos.client, &hellos.server) {
+                        self.resumed = resumed;
+                        self.hellos = None;
+                    }
+                }
+            }
+        }
+        self.hellos = None;
+
+        // Try to send our last flight right away, but leave it buffered if
+        // the transport is not ready. It is sent by the next read or write.
+        match self.write_pending() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res,
+        }
+    }
+
+    fn process_new_packets(&mut self) -> io::Result<()> {
+        let res = match *self.session {
+            Sess::Client(ref mut s, addr) => with_server_addr(addr, || s.process_new_packets()),
+            Sess::Server(ref mut s) => s.process_new_packets(),
+        };
+        match res {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                // Get the alert describing the failure to the peer if
+                // possible, but report the original error.
+                let _ = self.write_pending();
+                Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+        }
+    }
+
+    fn write_tls(&mut self) -> io::Result<()> {
+        let n = match self.hellos {
+            Some(ref mut hellos) => self.session.get_mut().write_tls(&mut Recording {
+                io: &mut self.io,
+                buf: &mut hellos.client,
+            })?,
+            None => self.session.get_mut().write_tls(&mut self.io)?,
+        };
+        match n {
+            0 => Err(io::ErrorKind::WriteZero.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        while self.session.get().wants_write() {
+            self.write_tls()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn shutdown(&mut self) -> io::Result<()> {
+        if !self.close_notify_sent {
+            self.session.get_mut().send_close_notify();
+            self.close_notify_sent = true;
+        }
+        self.write_pending()?;
+        self.io.flush()
+    }
+}
+
+impl<IO: Read + Write> Read for RustlsStream<IO> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // Alerts or handshake messages produced while processing
+            // earlier records go out first, as far as the transport allows.
+            match self.write_pending() {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                res => res?,
+            }
+
+            match self.session.get_mut().read(buf) {
+                Ok(0) if !buf.is_empty() && !self.eof => {}
+                // rustls reports a received close_notify this way.
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => {
+                    self.close_notify_received = true;
+                    return Ok(0);
+                }
+                res => return res,
+            }
+
+            if self.session.get_mut().read_tls(&mut self.io)? == 0 {
+                self.eof = true;
+            }
+            self.process_new_packets()?;
+        }
+    }
+}
+
+impl<IO: Read + Write> Write for RustlsStream<IO> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Make room for the new records by sending what is still buffered.
+        self.write_pending()?;
+        let n = self.session.get_mut().write(buf)?;
+
+        // The data is accepted at this point, so errors are left to be
+        // reported by the next call.
+        let _ = self.write_pending();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.get_mut().flush()?;
+        self.write_pending()?;
+        self.io.flush()
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+use x509_parser::time::ASN1Time;
+
+/// An X.509 certificate presented by the remote end of a `TlsStream`.
+///
+/// The certificate is stored in its DER encoding, which is always available
+/// through `der`. A structured view of the commonly inspected fields can be
+/// obtained with `parse`.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Certificate {
+    der: Vec<u8>,
+}
+
+/// The parsed fields of a `Certificate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedCertificate {
+    subject: String,
+    issuer: String,
+    subject_alt_names: Vec<SubjectAltName>,
+    not_before: SystemTime,
+    not_after: SystemTime,
+}
+
+/// An entry of a certificate's subject alternative name extension.
+///
+/// Only the name forms in common use are represented; other forms are
+/// skipped when parsing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubjectAltName {
+    /// A DNS name, such as `example.com` or `*.example.com`.
+    Dns(String),
+    /// An IPv4 or IPv6 address.
+    Ip(IpAddr),
+    /// A uniform resource identifier, such as a SPIFFE ID.
+    Uri(String),
+    /// An RFC 822 e-mail address.
+    Email(String),
+}
+
+impl Certificate {
+    /// Creates a certificate from its DER encoding.
+    ///
+    /// The encoding is not validated until `parse` is called.
+    pub fn from_der(der: Vec<u8>) -> Certificate {
+        Certificate { der }
+    }
+
+    /// Returns the DER encoding of this certificate.
+    pub fn der(&self) -> &[u8] {
+        &self.der
+    }
+
+    /// Consumes this certificate, returning its DER encoding.
+    pub fn into_der(self) -> Vec<u8> {
+        self.der
+    }
+
+    /// Parses the subject, issuer, subject alternative names and validity
+    /// period out of this certificate.
+    ///
+    /// Returns an error of kind `InvalidData` if the DER encoding is not a
+    /// well-formed X.509 certificate.
+    pub fn parse(&self) -> io::Result<ParsedCertificate> {
+        let (_, cert) = X509Certificate::from_der(&self.der).map_err(invalid_data)?;
+
+        let mut subject_alt_names = Vec::new();
+        if let Some(ext) = cert.subject_alternative_name().map_err(invalid_data)? {
+            for name in &ext.value.general_names {
+                let name = match *name {
+                    GeneralName::DNSName(s) => SubjectAltName::Dns(s.to_string()),
+                    GeneralName::URI(s) => SubjectAltName::Uri(s.to_string()),
+                    GeneralName::RFC822Name(s) => SubjectAltName::Email(s.to_string()),
+                    GeneralName::IPAddress(ip) => match ip_addr(ip) {
+                        Some(ip) => SubjectAltName::Ip(ip),
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                subject_alt_names.push(name);
+            }
+        }
+
+        Ok(ParsedCertificate {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            subject_alt_names,
+            not_before: system_time(cert.validity().not_before),
+            not_after: system_time(cert.validity().not_after),
+        })
+    }
+
+    /// Returns the DER encoding of this certificate's SubjectPublicKeyInfo.
+    pub(crate) fn public_key_info(&self) -> io::Result<&[u8]> {
+        let (_, cert) = X509Certificate::from_der(&self.der).map_err(invalid_data)?;
+        Ok(cert.tbs_certificate.subject_pki.raw)
+    }
+}
+
+impl fmt::Debug for Certificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Certificate")
+            .field("len", &self.der.len())
+            .finish()
+    }
+}
+
+impl ParsedCertificate {
+    /// Returns the subject distinguished name, formatted as in RFC 4514
+    /// (e.g. `C=US, O=Example, CN=example.com`).
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// Returns the issuer distinguished name, formatted like `subject`.
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    /// Returns the entries of the subject alternative name extension, in the
+    /// order they appear in the certificate.
+    pub fn subject_alt_names(&self) -> &[SubjectAltName] {
+        &self.subject_alt_names
+    }
+
+    /// Returns the time before which the certificate is not valid.
+    pub fn not_before(&self) -> SystemTime {
+        self.not_before
+    }
+
+    /// Returns the time after which the certificate is not valid.
+    pub fn not_after(&self) -> SystemTime {
+        self.not_after
+    }
+}
+
+fn invalid_data<E: fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn ip_addr(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => {
+            let mut octets = [0; 4];
+            octets.copy_from_slice(bytes);
+            Some(Ipv4Addr::from(octets).into())
+        }
+        16 => {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(bytes);
+            Some(Ipv6Addr::from(octets).into())
+        }
+        _ => None,
+    }
+}
+
+fn system_time(time: ASN1Time) -> SystemTime {
+    let secs = time.timestamp();
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
@@ -0,0 +1,29 @@
+use crate::Certificate;
+
+/// Whether an acceptor asks clients to authenticate with a certificate, set
+/// with `TlsAcceptor::set_client_auth`.
+///
+/// Client certificates are verified against the given trusted roots, which
+/// are usually the certificates of the CAs issuing them. A client which
+/// presents a certificate that does not verify is always rejected.
+#[derive(Debug, Clone)]
+pub enum ClientAuth {
+    /// Clients are not asked for a certificate. This is the default.
+    None,
+    /// Clients are asked for a certificate, but connections without one are
+    /// accepted as well.
+    Optional(Vec<Certificate>),
+    /// Clients must present a certificate issued by one of the roots.
+    Required(Vec<Certificate>),
+}
+
+impl ClientAuth {
+    /// Returns the trusted roots, or `None` if clients are not asked for a
+    /// certificate.
+    pub(crate) fn roots(&self) -> Option<&[Certificate]> {
+        match *self {
+            ClientAuth::None => None,
+            ClientAuth::Optional(ref roots) | ClientAuth::Required(ref roots) => Some(roots),
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! A minimal parser for the ClientHello message which opens a TLS handshake.
+//!
+//! Only the parts of the message describing what the client offers are
+//! extracted. The bytes are never consumed: they are replayed to the TLS
+//! backend, which performs the actual validation of the handshake.
+
+use crate::{fingerprint, Error};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_GROUPS: u16 = 10;
+const EXTENSION_EC_POINT_FORMATS: u16 = 11;
+const EXTENSION_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXTENSION_ALPN: u16 = 16;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// The largest record payload a TLS 1.2 or 1.3 peer may send, including the
+/// allowance for compression and encryption overhead.
+const MAX_RECORD_LEN: usize = (1 << 14) + 2048;
+
+/// The parameters a client offered in the ClientHello which opened its
+/// handshake.
+///
+/// Lists hold the values in the order the client sent them, including any
+/// GREASE values (RFC 8701). The message has only been parsed, not validated:
+/// the TLS backend may still reject it once the handshake starts.
+#[derive(Debug, Clone)]
+pub struct ClientHello {
+    legacy_version: u16,
+    cipher_suites: Vec<u16>,
+    extensions: Vec<u16>,
+    server_name: Option<String>,
+    alpn_protocols: Vec<Vec<u8>>,
+    supported_versions: Vec<u16>,
+    supported_groups: Vec<u16>,
+    ec_point_formats: Vec<u8>,
+    signature_algorithms: Vec<u16>,
+}
+
+/// Inspects the ClientHello of each connection before it is accepted.
+///
+/// A hook is installed with `TlsAcceptor::set_client_hello_hook`. It is also
+/// implemented for closures taking the ClientHello.
+pub trait ClientHelloHook: Send + Sync {
+    /// Decides whether the handshake offered by `hello` may continue.
+    ///
+    /// Returning an error aborts the handshake, and `TlsAcceptor::accept`
+    /// fails with that error. The connection is closed without sending
+    /// anything to the client.
+    fn inspect(&self, hello: &ClientHello) -> Result<(), Error>;
+}
+
+impl<F> ClientHelloHook for F
+where
+    F: Fn(&ClientHello) -> Result<(), Error> + Send + Sync,
+{
+    fn inspect(&self, hello: &ClientHello) -> Result<(), Error> {
+        self(hello)
+    }
+}
+
+pub(crate) enum Parse {
+    /// The buffer starts with a complete ClientHello.
+    Done(ClientHello),
+    /// The buffer holds the beginning of what may be a ClientHello.
+    Partial,
+    /// The buffer does not start with a well-formed ClientHello.
+    Invalid,
+}
+
+impl ClientHello {
+    /// Parses the ClientHello at the start of `buf`, which holds the raw bytes
+    /// sent by a client, starting with the record header.
+    ///
+    /// Returns `None` if `buf` does not start with a complete, well-formed
+    /// ClientHello. This allows inspecting handshakes captured elsewhere.
+    pub fn from_bytes(buf: &[u8]) -> Option<ClientHello> {
+        match parse(buf) {
+            Parse::Done(hello) => Some(hello),
+            Parse::Partial | Parse::Invalid => None,
+        }
+    }
+
+    /// Returns the protocol version in the legacy_version field, e.g.
+    /// `0x0303` for TLS 1.2.
+    ///
+    /// Clients offering TLS 1.3 send `0x0303` here, and list the versions
+    /// they support in the supported_versions extension instead.
+    pub fn legacy_version(&self) -> u16 {
+        self.legacy_version
+    }
+
+    /// Returns the versions listed in the supported_versions extension,
+    /// which is empty if the client did not send it.
+    pub fn supported_versions(&self) -> &[u16] {
+        &self.supported_versions
+    }
+
+    /// Returns the cipher suites offered by the client, in order of its
+    /// preference.
+    pub fn cipher_suites(&self) -> &[u16] {
+        &self.cipher_suites
+    }
+
+    /// Returns the types of the extensions sent by the client.
+    pub fn extensions(&self) -> &[u16] {
+        &self.extensions
+    }
+
+    /// Returns the host name sent in the server_name extension, if any.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// Returns the protocols offered through ALPN, e.g. `b"h2"`, which is
+    /// empty if the client did not send the extension.
+    pub fn alpn_protocols(&self) -> &[Vec<u8>] {
+        &self.alpn_protocols
+    }
+
+    /// Returns the groups listed in the supported_groups extension.
+    pub fn supported_groups(&self) -> &[u16] {
+        &self.supported_groups
+    }
+
+    /// Returns the formats listed in the ec_point_formats extension.
+    pub fn ec_point_formats(&self) -> &[u8] {
+        &self.ec_point_formats
+    }
+
+    /// Returns the schemes listed in the signature_algorithms extension.
+    pub fn signature_algorithms(&self) -> &[u16] {
+        &self.signature_algorithms
+    }
+
+    /// Returns the JA3 fingerprint of this ClientHello, the MD5 digest of
+    /// `ja3_string` in hex.
+    pub fn ja3(&self) -> String {
+        fingerprint::ja3(self)
+    }
+
+    /// Returns the string the JA3 fingerprint is computed from, which lists
+    /// the version, cipher suites, extensions, groups and point formats.
+    pub fn ja3_string(&self) -> String {
+        fingerprint::ja3_string(self)
+    }
+
+    /// Returns the JA4 fingerprint of this ClientHello, such as
+    /// `t13d1516h2_8daaf6152771_e5627efa2ab1`.
+    ///
+    /// The fingerprint is computed as for connections over TCP.
+    pub fn ja4(&self) -> String {
+        fingerprint::ja4(self)
+    }
+}
+
+/// Parses the ClientHello at the start of `buf`, which holds the raw bytes
+/// received from the client.
+///
+/// The message may be fragmented over several handshake records.
+pub(crate) fn parse(buf: &[u8]) -> Parse {
+    let mut msg = Vec::new();
+    let mut rest = buf;
+
+    loop {
+        if rest.len() < 5 {
+            return Parse::Partial;
+        }
+        let len = usize::from(u16::from_be_bytes([rest[3], rest[4]]));
+        if rest[0] != CONTENT_TYPE_HANDSHAKE || len == 0 || len > MAX_RECORD_LEN {
+            return Parse::Invalid;
+        }
+        if rest.len() < 5 + len {
+            return Parse::Partial;
+        }
+        msg.extend_from_slice(&rest[5..5 + len]);
+        rest = &rest[5 + len..];
+
+        if msg.len() < 4 {
+            continue;
+        }
+        if msg[0] != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return Parse::Invalid;
+        }
+        let body_len = usize::from(msg[1]) << 16 | usize::from(msg[2]) << 8 | usize::from(msg[3]);
+        if msg.len() < 4 + body_len {
+            continue;
+        }
+        return match parse_body(Reader(&msg[4..4 + body_len])) {
+            Some(hello) => Parse::Done(hello),
+            None => Parse::Invalid,
+        };
+    }
+}
+
+fn parse_body(mut r: Reader<'_>) -> Option<ClientHello> {
+    let legacy_version = r.u16()?;
+    let _random = r.bytes(32)?;
+    let _session_id = r.vec8()?;
+    let cipher_suites = r.vec16()?.u16_list()?;
+    let _compression_methods = r.vec8()?;
+
+    let mut hello = ClientHello {
+        legacy_version,
+        cipher_suites,
+        extensions: Vec::new(),
+        server_name: None,
+        alpn_protocols: Vec::new(),
+        supported_versions: Vec::new(),
+        supported_groups: Vec::new(),
+        ec_point_formats: Vec::new(),
+        signature_algorithms: Vec::new(),
+    };
+    if r.is_empty() {
+        // Extensions are optional in TLS 1.2 and below.
+        return Some(hello);
+    }
+
+    let mut extensions = r.vec16()?;
+    while !extensions.is_empty() {
+        let typ = extensions.u16()?;
+        let mut data = extensions.vec16()?;
+        hello.extensions.push(typ);
+        match typ {
+            EXTENSION_SERVER_NAME => hello.server_name = parse_server_name(data)?,
+            EXTENSION_SUPPORTED_GROUPS => hello.supported_groups = data.vec16()?.u16_list()?,
+            EXTENSION_EC_POINT_FORMATS => hello.ec_point_formats = data.vec8()?.0.to_vec(),
+            EXTENSION_SIGNATURE_ALGORITHMS => {
+                hello.signature_algorithms = data.vec16()?.u16_list()?
+            }
+            EXTENSION_ALPN => hello.alpn_protocols = parse_alpn(data)?,
+            EXTENSION_SUPPORTED_VERSIONS => hello.supported_versions = data.vec8()?.u16_list()?,
+            _ => {}
+        }
+    }
+    Some(hello)
+}
+
+fn parse_alpn(mut r: Reader<'_>) -> Option<Vec<Vec<u8>>> {
+    let mut list = r.vec16()?;
+    let mut protocols = Vec::new();
+    while !list.is_empty() {
+        protocols.push(list.vec8()?.0.to_vec());
+    }
+    Some(protocols)
+}
+
+fn parse_server_name(mut r: Reader<'_>) -> Option<Option<String>> {
+    let mut names = r.vec16()?;
+    while !names.is_empty() {
+        let typ = names.u8()?;
+        let name = names.vec16()?;
+        if typ == SERVER_NAME_TYPE_HOST_NAME {
+            let name = std::str::from_utf8(name.0).ok()?;
+            return Some(Some(name.to_owned()));
+        }
+    }
+    Some(None)
+}
+
+/// Reads the fields of a TLS message, failing once the input is exhausted.
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    pub(crate) fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    pub(crate) fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()?;
+        self.bytes(usize::from(len)).map(Reader)
+    }
+
+    pub(crate) fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()?;
+        self.bytes(usize::from(len)).map(Reader)
+    }
+
+    /// Reads the rest of the input as a list of 16-bit values.
+    pub(crate) fn u16_list(mut self) -> Option<Vec<u16>> {
+        let mut list = Vec::with_capacity(self.0.len() / 2);
+        while !self.is_empty() {
+            list.push(self.u16()?);
+        }
+        Some(list)
+    }
+}
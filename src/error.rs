@@ -0,0 +1,328 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+
+/// An error returned by the TLS operations of this crate.
+///
+/// Errors reported by the TLS backend, or by the underlying stream, are
+/// available as this error's `source`. Handshakes that did not complete
+/// within the configured handshake timeout are reported with `is_timeout`
+/// returning `true`. `kind` classifies the error the same way regardless of
+/// the backend which reported it.
+pub struct Error {
+    kind: Kind,
+}
+
+/// The class of an `Error`, as returned by `Error::kind`.
+///
+/// The classification is made from what the backend reports, which is not
+/// equally detailed for all of them. In particular, `native-tls` on macOS
+/// reports all rejected certificates as `InvalidCertificate`, and rustls does
+/// not tell self-signed certificates apart from ones with an untrusted root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The peer's certificate has expired, or is not valid yet.
+    CertificateExpired,
+    /// The peer's certificate is not valid for the expected name.
+    HostnameMismatch,
+    /// The peer's certificate chain does not lead to a trusted root.
+    UntrustedRoot,
+    /// The peer's certificate is self-signed, and not trusted.
+    SelfSigned,
+    /// The peer's certificate was rejected for another reason, including by
+    /// a `CertificateVerifier`.
+    InvalidCertificate,
+    /// The peers do not support a common protocol version.
+    ProtocolVersion,
+    /// The peer aborted the handshake with an alert.
+    AlertReceived,
+    /// Reading from or writing to the underlying stream failed, or it was
+    /// closed unexpectedly.
+    Io,
+    /// The handshake did not complete within the configured timeout.
+    Timeout,
+    /// Any other error, such as a malformed message or an invalid
+    /// configuration.
+    Other,
+}
+
+enum Kind {
+    NativeTls(native_tls::Error),
+    /// The error, and the result of verifying the peer's certificate chain.
+    #[cfg(feature = "openssl")]
+    OpenSsl(openssl::ssl::Error, openssl::x509::X509VerifyResult),
+    #[cfg(feature = "rustls")]
+    Rustls(rustls::TLSError),
+    Io(io::Error),
+    Timeout,
+    /// The server's certificate was rejected by a `CertificateVerifier`, for
+    /// the given reason.
+    Rejected(String),
+}
+
+impl Error {
+    pub(crate) fn timeout() -> Error {
+        Error {
+            kind: Kind::Timeout,
+        }
+    }
+
+    pub(crate) fn rejected(reason: String) -> Error {
+        Error {
+            kind: Kind::Rejected(reason),
+        }
+    }
+
+    /// Creates an error for a failed OpenSSL handshake, given the result of
+    /// verifying the peer's certificate chain.
+    #[cfg(feature = "openssl")]
+    pub(crate) fn openssl_handshake(
+        err: openssl::ssl::Error,
+        verify: openssl::x509::X509VerifyResult,
+    ) -> Error {
+        Error {
+            kind: Kind::OpenSsl(err, verify),
+        }
+    }
+
+    /// Returns the class of this error.
+    pub fn kind(&self) -> ErrorKind {
+        match self.kind {
+            Kind::NativeTls(ref e) => match io_source(e) {
+                Some(_) => ErrorKind::Io,
+                None => classify_message(&e.to_string()),
+            },
+            #[cfg(feature = "openssl")]
+            Kind::OpenSsl(ref e, verify) => {
+                if verify != openssl::x509::X509VerifyResult::OK {
+                    classify_verify_result(verify.as_raw())
+                } else if e.io_error().is_some() {
+                    ErrorKind::Io
+                } else {
+                    classify_message(&e.to_string())
+                }
+            }
+            #[cfg(feature = "rustls")]
+            Kind::Rustls(ref e) => classify_rustls(e),
+            Kind::Io(_) => ErrorKind::Io,
+            Kind::Timeout => ErrorKind::Timeout,
+            Kind::Rejected(_) => ErrorKind::InvalidCertificate,
+        }
+    }
+
+    /// Returns `true` if the handshake was aborted because it did not
+    /// complete within the configured handshake timeout.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self.kind, Kind::Timeout)
+    }
+}
+
+impl From<native_tls::Error> for Error {
+    fn from(err: native_tls::Error) -> Error {
+        Error {
+            kind: Kind::NativeTls(err),
+        }
+    }
+}
+
+#[cfg(feature = "openssl")]
+impl From<openssl::ssl::Error> for Error {
+    fn from(err: openssl::ssl::Error) -> Error {
+        Error {
+            kind: Kind::OpenSsl(err, openssl::x509::X509VerifyResult::OK),
+        }
+    }
+}
+
+#[cfg(feature = "openssl")]
+impl From<openssl::error::ErrorStack> for Error {
+    fn from(err: openssl::error::ErrorStack) -> Error {
+        Error::from(openssl::ssl::Error::from(err))
+    }
+}
+
+#[cfg(feature = "rustls")]
+impl From<rustls::TLSError> for Error {
+    fn from(err: rustls::TLSError) -> Error {
+        Error {
+            kind: Kind::Rustls(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        // rustls reports protocol errors wrapped in an `io::Error`.
+        #[cfg(feature = "rustls")]
+        let err = match err.get_ref().map(|e| e.is::<rustls::TLSError>()) {
+            Some(true) => {
+                let inner = err.into_inner().unwrap();
+                return Error::from(*inner.downcast::<rustls::TLSError>().unwrap());
+            }
+            _ => err,
+        };
+
+        Error {
+            kind: Kind::Io(err),
+        }
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Kind::NativeTls(ref e) => f.debug_tuple("NativeTls").field(e).finish(),
+            #[cfg(feature = "openssl")]
+            Kind::OpenSsl(ref e, _) => f.debug_tuple("OpenSsl").field(e).finish(),
+            #[cfg(feature = "rustls")]
+            Kind::Rustls(ref e) => f.debug_tuple("Rustls").field(e).finish(),
+            Kind::Io(ref e) => f.debug_tuple("Io").field(e).finish(),
+            Kind::Timeout => f.write_str("Timeout"),
+            Kind::Rejected(ref r) => f.debug_tuple("Rejected").field(r).finish(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            Kind::NativeTls(ref e) => fmt::Display::fmt(e, f),
+            #[cfg(feature = "openssl")]
+            Kind::OpenSsl(ref e, _) => fmt::Display::fmt(e, f),
+            #[cfg(feature = "rustls")]
+            Kind::Rustls(ref e) => fmt::Display::fmt(e, f),
+            Kind::Io(ref e) => fmt::Display::fmt(e, f),
+            Kind::Timeout => f.write_str("TLS handshake timed out"),
+            Kind::Rejected(ref r) => write!(f, "server certificate rejected: {}", r),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self.kind {
+            Kind::NativeTls(ref e) => Some(e),
+            #[cfg(feature = "openssl")]
+            Kind::OpenSsl(ref e, _) => Some(e),
+            #[cfg(feature = "rustls")]
+            Kind::Rustls(ref e) => Some(e),
+            Kind::Io(ref e) => Some(e),
+            Kind::Timeout | Kind::Rejected(_) => None,
+        }
+    }
+}
+
+/// Returns the I/O error at the root of `err`, if any.
+fn io_source<'a>(err: &'a (dyn StdError + 'static)) -> Option<&'a io::Error> {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return Some(err);
+        }
+        source = err.source();
+    }
+    None
+}
+
+/// Classifies an error from its message, which is all `native-tls` exposes.
+///
+/// This also classifies OpenSSL errors which are not about certificates, from
+/// the reasons in their error stack.
+fn classify_message(msg: &str) -> ErrorKind {
+    const PATTERNS: &[(&str, ErrorKind)] = &[
+        // OpenSSL certificate verification results, which native-tls appends
+        // to its messages.
+        ("certificate has expired", ErrorKind::CertificateExpired),
+        (
+            "certificate is not yet valid",
+            ErrorKind::CertificateExpired,
+        ),
+        ("hostname mismatch", ErrorKind::HostnameMismatch),
+        (
+            "self-signed certificate in certificate chain",
+            ErrorKind::UntrustedRoot,
+        ),
+        (
+            "self signed certificate in certificate chain",
+            ErrorKind::UntrustedRoot,
+        ),
+        ("self-signed certificate", ErrorKind::SelfSigned),
+        ("self signed certificate", ErrorKind::SelfSigned),
+        (
+            "unable to get local issuer certificate",
+            ErrorKind::UntrustedRoot,
+        ),
+        ("unable to get issuer certificate", ErrorKind::UntrustedRoot),
+        ("certificate verify failed", ErrorKind::InvalidCertificate),
+        // SChannel.
+        (
+            "not within its validity period",
+            ErrorKind::CertificateExpired,
+        ),
+        ("cn name does not match", ErrorKind::HostnameMismatch),
+        ("principal name is incorrect", ErrorKind::HostnameMismatch),
+        (
+            "root certificate which is not trusted",
+            ErrorKind::UntrustedRoot,
+        ),
+        (
+            "issued by an authority that is not trusted",
+            ErrorKind::UntrustedRoot,
+        ),
+        // Security framework.
+        ("was not trusted", ErrorKind::InvalidCertificate),
+        // OpenSSL handshake failures. Alerts received from the peer have
+        // reasons such as "tlsv1 alert protocol version".
+        ("alert protocol version", ErrorKind::ProtocolVersion),
+        ("wrong version number", ErrorKind::ProtocolVersion),
+        ("unsupported protocol", ErrorKind::ProtocolVersion),
+        ("no protocols available", ErrorKind::ProtocolVersion),
+        ("version too low", ErrorKind::ProtocolVersion),
+        ("alert", ErrorKind::AlertReceived),
+        ("unexpected eof", ErrorKind::Io),
+    ];
+
+    let msg = msg.to_ascii_lowercase();
+    PATTERNS
+        .iter()
+        .find(|(pattern, _)| msg.contains(pattern))
+        .map_or(ErrorKind::Other, |(_, kind)| *kind)
+}
+
+/// Classifies an OpenSSL certificate verification result.
+#[cfg(feature = "openssl")]
+fn classify_verify_result(code: std::os::raw::c_int) -> ErrorKind {
+    // The X509_V_ERR_* codes of OpenSSL.
+    match code {
+        9 | 10 => ErrorKind::CertificateExpired,
+        18 => ErrorKind::SelfSigned,
+        2 | 19 | 20 | 21 | 27 => ErrorKind::UntrustedRoot,
+        62..=64 => ErrorKind::HostnameMismatch,
+        _ => ErrorKind::InvalidCertificate,
+    }
+}
+
+#[cfg(feature = "rustls")]
+fn classify_rustls(err: &rustls::TLSError) -> ErrorKind {
+    use rustls::internal::msgs::enums::AlertDescription;
+    use rustls::TLSError;
+
+    match *err {
+        TLSError::WebPKIError(webpki::Error::CertExpired)
+        | TLSError::WebPKIError(webpki::Error::CertNotValidYet) => ErrorKind::CertificateExpired,
+        TLSError::WebPKIError(webpki::Error::CertNotValidForName) => ErrorKind::HostnameMismatch,
+        TLSError::WebPKIError(webpki::Error::UnknownIssuer) => ErrorKind::UntrustedRoot,
+        TLSError::WebPKIError(_) | TLSError::NoCertificatesPresented => {
+            ErrorKind::InvalidCertificate
+        }
+        TLSError::AlertReceived(AlertDescription::ProtocolVersion) => ErrorKind::ProtocolVersion,
+        TLSError::AlertReceived(_) => ErrorKind::AlertReceived,
+        // rustls reports unsupported versions as an incompatibility naming
+        // the versions.
+        TLSError::PeerIncompatibleError(ref why) if why.contains("TLS") => {
+            ErrorKind::ProtocolVersion
+        }
+        _ => ErrorKind::Other,
+    }
+}
@@ -0,0 +1,149 @@
+//! JA3 and JA4 fingerprints of the ClientHello sent by a client.
+//!
+//! Both fingerprints summarize the parameters a client offered, which mostly
+//! depend on the TLS library and its configuration rather than on the
+//! connection, so they identify the kind of client. JA3 is defined at
+//! <https://github.com/salesforce/ja3> and JA4 at
+//! <https://github.com/FoxIO-LLC/ja4>.
+
+use crate::ClientHello;
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use std::fmt::Write;
+
+/// The fingerprints of a client, computed during the handshake.
+#[derive(Debug, Clone)]
+pub(crate) struct Fingerprint {
+    pub(crate) ja3: String,
+    pub(crate) ja4: String,
+}
+
+impl Fingerprint {
+    pub(crate) fn new(hello: &ClientHello) -> Fingerprint {
+        Fingerprint {
+            ja3: ja3(hello),
+            ja4: ja4(hello),
+        }
+    }
+}
+
+/// Returns whether `value` is one of the reserved GREASE values of RFC 8701,
+/// which clients send at random and fingerprints ignore.
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn join<T: ToString>(values: impl Iterator<Item = T>, sep: &str) -> String {
+    values.map(|v| v.to_string()).collect::<Vec<_>>().join(sep)
+}
+
+fn non_grease(values: &[u16]) -> impl Iterator<Item = u16> + '_ {
+    values.iter().copied().filter(|v| !is_grease(*v))
+}
+
+pub(crate) fn ja3_string(hello: &ClientHello) -> String {
+    format!(
+        "{},{},{},{},{}",
+        hello.legacy_version(),
+        join(non_grease(hello.cipher_suites()), "-"),
+        join(non_grease(hello.extensions()), "-"),
+        join(non_grease(hello.supported_groups()), "-"),
+        join(hello.ec_point_formats().iter(), "-"),
+    )
+}
+
+pub(crate) fn ja3(hello: &ClientHello) -> String {
+    hex(&Md5::digest(ja3_string(hello).as_bytes()))
+}
+
+pub(crate) fn ja4(hello: &ClientHello) -> String {
+    let version = non_grease(hello.supported_versions())
+        .max()
+        .unwrap_or_else(|| hello.legacy_version());
+    let version = match version {
+        0x0304 => "13",
+        0x0303 => "12",
+        0x0302 => "11",
+        0x0301 => "10",
+        0x0300 => "s3",
+        0x0002 => "s2",
+        _ => "00",
+    };
+    let sni = if hello.server_name().is_some() {
+        'd'
+    } else {
+        'i'
+    };
+    let ciphers = non_grease(hello.cipher_suites()).collect::<Vec<_>>();
+    let extensions = non_grease(hello.extensions()).collect::<Vec<_>>();
+    let alpn = match hello.alpn_protocols().first() {
+        Some(p) if !p.is_empty() => {
+            let (first, last) = (p[0], p[p.len() - 1]);
+            if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+                format!("{}{}", first as char, last as char)
+            } else {
+                // Only the outer hex digits of the protocol are used then.
+                let first = format!("{:02x}", first);
+                let last = format!("{:02x}", last);
+                format!("{}{}", &first[..1], &last[1..])
+            }
+        }
+        _ => "00".to_owned(),
+    };
+    let a = format!(
+        "t{}{}{:02}{:02}{}",
+        version,
+        sni,
+        ciphers.len().min(99),
+        extensions.len().min(99),
+        alpn
+    );
+
+    let mut ciphers = ciphers
+        .iter()
+        .map(|c| format!("{:04x}", c))
+        .collect::<Vec<_>>();
+    ciphers.sort();
+    let b = truncated_sha256(&ciphers.join(","), ciphers.is_empty());
+
+    // The server_name and ALPN extensions are already part of the first
+    // section.
+    let mut extensions = extensions
+        .iter()
+        .filter(|e| **e != 0x0000 && **e != 0x0010)
+        .map(|e| format!("{:04x}", e))
+        .collect::<Vec<_>>();
+    extensions.sort();
+    let mut c = extensions.join(",");
+    let algorithms = non_grease(hello.signature_algorithms())
+        .map(|s| format!("{:04x}", s))
+        .collect::<Vec<_>>();
+    if !algorithms.is_empty() {
+        c.push('_');
+        c.push_str(&algorithms.join(","));
+    }
+    let c = truncated_sha256(&c, extensions.is_empty());
+
+    format!("{}_{}_{}", a, b, c)
+}
+
+/// Returns the first 12 hex digits of the SHA-256 digest of `list`, or zeros
+/// if the list it was made from is empty.
+fn truncated_sha256(list: &str, empty: bool) -> String {
+    if empty {
+        return "0".repeat(12);
+    }
+    let mut digest = hex(&Sha256::digest(list.as_bytes()));
+    digest.truncate(12);
+    digest
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
@@ -21,6 +21,18 @@
 //! Windows this library uses SChannel, on OSX it uses SecureTransport, and on
 //! other platforms it uses OpenSSL.
 //!
+//! With the `rustls` feature enabled, `TlsConnector` and `TlsAcceptor` can
+//! alternatively be created from a `rustls::ClientConfig` or
+//! `rustls::ServerConfig`, giving a pure-Rust TLS stack with the same API.
+//! The backend is picked per connector or acceptor, and the resulting
+//! `TlsStream`s behave the same regardless of which one negotiated them.
+//!
+//! Similarly, the `openssl` feature allows creating them from an
+//! `openssl::ssl::SslConnector` or `openssl::ssl::SslAcceptor`. This gives
+//! access to OpenSSL specific settings, such as cipher lists, curves or verify
+//! callbacks, which `native-tls` does not expose. The underlying `SslRef` of
+//! an established stream is available through `TlsStream::ssl`.
+//!
 //! Each TLS stream implements the `Read` and `Write` traits to interact and
 //! interoperate with the rest of the futures I/O ecosystem. Client connections
 //! initiated from this crate verify hostnames automatically and by default.
@@ -30,24 +42,81 @@
 //! functionality provided by the `native-tls` crate, on which this crate is
 //! built. Configuration of TLS parameters is still primarily done through the
 //! `native-tls` crate.
+//!
+//! # ALPN
+//!
+//! With the `alpn` feature enabled, the protocols offered by a client are
+//! configured with `native_tls::TlsConnectorBuilder::request_alpns` and the
+//! protocols accepted by a server with
+//! `native_tls::TlsAcceptorBuilder::accept_alpn`. Once the handshake has
+//! completed, `TlsStream::negotiated_alpn` reports which protocol, if any, was
+//! selected, e.g. to decide between HTTP/2 and HTTP/1.1 for a connection.
+
+mod allow_std;
+mod backend;
+mod cert;
+mod client_auth;
+mod client_hello;
+mod error;
+mod fingerprint;
+mod listener;
+mod pin;
+mod reload;
+mod resumption;
+mod session;
+mod sni;
+mod sniff;
+mod split;
+pub mod starttls;
+mod verify;
+
+pub use crate::cert::{Certificate, ParsedCertificate, SubjectAltName};
+pub use crate::client_auth::ClientAuth;
+pub use crate::client_hello::{ClientHello, ClientHelloHook};
+pub use crate::error::{Error, ErrorKind};
+pub use crate::listener::TlsListener;
+pub use crate::pin::PinSet;
+pub use crate::reload::{IdentityFiles, ReloadHandle, Watch};
+pub use crate::resumption::{SessionResumption, TicketKeys};
+pub use crate::session::SessionCacheStats;
+pub use crate::sni::{ServerNameMap, ServerNameResolver};
+pub use crate::sniff::{AcceptOrPlaintext, MaybeTlsStream, PlaintextStream};
+pub use crate::split::{ReadHalf, WriteHalf};
+pub use crate::verify::CertificateVerifier;
 
+#[cfg(feature = "openssl")]
+pub use openssl;
+#[cfg(feature = "rustls")]
+pub use rustls;
+#[cfg(feature = "rustls")]
+pub use webpki;
+
+use bytes::Buf;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use native_tls::{Error, HandshakeError, MidHandshakeTlsStream};
+use crate::allow_std::{AllowStd, BufferSizes};
+use crate::backend::HandshakeError;
+use crate::fingerprint::Fingerprint;
+use crate::session::{ClientSessions, SessionCache};
+use crate::sni::ReadClientHello;
+use crate::verify::ServerChecks;
 use std::fmt;
 use std::future::Future;
-use std::io::{self, Read, Write};
+use std::io::{self, IoSlice, Read, Write};
 use std::marker::Unpin;
 use std::mem::MaybeUninit;
+use std::net::SocketAddr;
 use std::pin::Pin;
-use std::ptr::null_mut;
+use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Delay;
 
-#[derive(Debug)]
-struct AllowStd<S> {
-    inner: S,
-    context: *mut (),
-}
+/// The largest amount of plaintext a single TLS record can hold.
+const MAX_RECORD_PLAINTEXT: usize = 16 * 1024;
+
+/// The most slices of a `Buf` considered by a single vectored write.
+const MAX_WRITE_SLICES: usize = 64;
 
 /// A wrapper around an underlying raw stream which implements the TLS or SSL
 /// protocol.
@@ -56,94 +125,124 @@ struct AllowStd<S> {
 /// and both the server and the client are ready for receiving and sending
 /// data. Bytes read from a `TlsStream` are decrypted from `S` and bytes written
 /// to a `TlsStream` are encrypted when passing through to `S`.
-#[derive(Debug)]
-pub struct TlsStream<S>(native_tls::TlsStream<AllowStd<S>>);
+pub struct TlsStream<S> {
+    inner: backend::Stream<AllowStd<S>>,
+    server_name: Option<String>,
+    fingerprint: Option<Fingerprint>,
+    sessions: Option<Box<ClientSessions>>,
+    allow_truncation: bool,
+    close_notify_sent: bool,
+    shutdown: bool,
+    coalesce: Vec<u8>,
+}
+
+/// A wrapper around a `native_tls::TlsConnector`, or another backend's client
+/// configuration, providing an async `connect` method.
+#[derive(Clone)]
+pub struct TlsConnector {
+    inner: backend::Connector,
+    handshake_timeout: Option<Duration>,
+    config: StreamConfig,
+    verifier: Option<Arc<dyn CertificateVerifier>>,
+    pins: Option<Arc<PinSet>>,
+}
 
-/// A wrapper around a `native_tls::TlsConnector`, providing an async `connect`
-/// method.
+/// A wrapper around a `native_tls::TlsAcceptor`, or another backend's server
+/// configuration, providing an async `accept` method.
 #[derive(Clone)]
-pub struct TlsConnector(native_tls::TlsConnector);
+pub struct TlsAcceptor {
+    inner: AcceptorInner,
+    handshake_timeout: Option<Duration>,
+    config: StreamConfig,
+    client_hello_hook: Option<Arc<dyn ClientHelloHook>>,
+}
 
-/// A wrapper around a `native_tls::TlsAcceptor`, providing an async `accept`
-/// method.
 #[derive(Clone)]
-pub struct TlsAcceptor(native_tls::TlsAcceptor);
+enum AcceptorInner {
+    Backend(backend::Acceptor),
+    Resolver(Arc<dyn ServerNameResolver>),
+    Reloadable(Arc<RwLock<TlsAcceptor>>),
+}
 
-struct MidHandshake<S>(Option<MidHandshakeTlsStream<AllowStd<S>>>);
+/// Future returned from `TlsConnector::connect` which will resolve once the
+/// client half of the handshake has completed.
+///
+/// The future owns everything it needs to make progress, so it is `'static`
+/// whenever `S` is and can be stored or boxed without borrowing the
+/// connector.
+pub struct Connect<S>(Handshake<S>);
 
-enum StartedHandshake<S> {
-    Done(TlsStream<S>),
-    Mid(MidHandshakeTlsStream<AllowStd<S>>),
+/// Future returned from `TlsAcceptor::accept` which will resolve once the
+/// server half of the handshake has completed.
+///
+/// The future owns everything it needs to make progress, so it is `'static`
+/// whenever `S` is and can be stored or boxed without borrowing the
+/// acceptor.
+pub struct Accept<S>(Handshake<S>);
+
+/// Future returned from `TlsStream::into_inner` which will resolve once the
+/// TLS session has been closed by both sides.
+///
+/// It resolves to the underlying stream and the bytes which were read from it
+/// past the end of the session.
+pub struct IntoInner<S> {
+    stream: Option<TlsStream<S>>,
+    /// Whether close_notify has been sent and flushed, so that only the
+    /// peer's close_notify is left to wait for.
+    closed: bool,
 }
 
-struct StartedHandshakeFuture<F, S>(Option<StartedHandshakeFutureInner<F, S>>);
-struct StartedHandshakeFutureInner<F, S> {
-    f: F,
-    stream: S,
+struct Handshake<S> {
+    state: HandshakeState<S>,
+    timeout: Option<Duration>,
+    config: StreamConfig,
+    delay: Option<Delay>,
+    server_name: Option<String>,
+    fingerprint: Option<Fingerprint>,
+    checks: Option<ServerChecks>,
+    sessions: Option<Box<ClientSessions>>,
 }
 
-struct Guard<'a, S>(&'a mut TlsStream<S>)
-where
-    AllowStd<S>: Read + Write;
+/// The settings of a connector or acceptor which apply to the streams it
+/// establishes.
+#[derive(Debug, Clone, Copy, Default)]
+struct StreamConfig {
+    buffer_sizes: BufferSizes,
+    allow_truncation: bool,
+    fingerprint_clients: bool,
+}
 
-impl<S> Drop for Guard<'_, S>
-where
-    AllowStd<S>: Read + Write,
-{
-    fn drop(&mut self) {
-        (self.0).0.get_mut().context = null_mut();
-    }
+enum HandshakeState<S> {
+    ClientHello(ReadClientHello<S>, TlsAcceptor),
+    Start(StartedHandshakeFuture<S>),
+    Mid(MidHandshake<S>),
+    Done,
 }
 
-// *mut () context is neither Send nor Sync
-unsafe impl<S: Send> Send for AllowStd<S> {}
-unsafe impl<S: Sync> Sync for AllowStd<S> {}
+struct MidHandshake<S>(Option<backend::MidHandshake<AllowStd<S>>>);
 
-impl<S> AllowStd<S>
-where
-    S: Unpin,
-{
-    fn with_context<F, R>(&mut self, f: F) -> R
-    where
-        F: FnOnce(&mut Context<'_>, Pin<&mut S>) -> R,
-    {
-        unsafe {
-            assert!(!self.context.is_null());
-            let waker = &mut *(self.context as *mut _);
-            f(waker, Pin::new(&mut self.inner))
-        }
-    }
+enum StartedHandshake<S> {
+    Done(TlsStream<S>),
+    Mid(backend::MidHandshake<AllowStd<S>>),
 }
 
-impl<S> Read for AllowStd<S>
-where
-    S: AsyncRead + Unpin,
-{
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        match self.with_context(|ctx, stream| stream.poll_read(ctx, buf)) {
-            Poll::Ready(r) => r,
-            Poll::Pending => Err(io::Error::from(io::ErrorKind::WouldBlock)),
-        }
-    }
+struct StartedHandshakeFuture<S>(Option<StartedHandshakeFutureInner<S>>);
+struct StartedHandshakeFutureInner<S> {
+    start: Start,
+    stream: AllowStd<S>,
 }
 
-impl<S> Write for AllowStd<S>
-where
-    S: AsyncWrite + Unpin,
-{
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.with_context(|ctx, stream| stream.poll_write(ctx, buf)) {
-            Poll::Ready(r) => r,
-            Poll::Pending => Err(io::Error::from(io::ErrorKind::WouldBlock)),
-        }
-    }
+enum Start {
+    /// Connects to a domain, and the server's address if known.
+    Connect(backend::Connector, String, Option<SocketAddr>),
+    Accept(backend::Acceptor),
+}
 
-    fn flush(&mut self) -> io::Result<()> {
-        match self.with_context(|ctx, stream| stream.poll_flush(ctx)) {
-            Poll::Ready(r) => r,
-            Poll::Pending => Err(io::Error::from(io::ErrorKind::WouldBlock)),
-        }
-    }
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "peer closed connection without sending TLS close_notify",
+    )
 }
 
 fn cvt<T>(r: io::Result<T>) -> Poll<io::Result<T>> {
@@ -155,14 +254,105 @@ fn cvt<T>(r: io::Result<T>) -> Poll<io::Result<T>> {
 }
 
 impl<S> TlsStream<S> {
-    fn with_context<F, R>(&mut self, ctx: &mut Context<'_>, f: F) -> R
+    fn new(mut inner: backend::Stream<AllowStd<S>>) -> TlsStream<S> {
+        inner.get_mut().buffer_writes();
+        TlsStream {
+            inner,
+            server_name: None,
+            fingerprint: None,
+            sessions: None,
+            allow_truncation: false,
+            close_notify_sent: false,
+            shutdown: false,
+            coalesce: Vec::new(),
+        }
+    }
+
+    /// Sends a close_notify alert and flushes it to the transport.
+    fn poll_close_notify(&mut self, ctx: &mut Context<'_>) -> Poll<io::Result<()>>
     where
-        F: FnOnce(&mut native_tls::TlsStream<AllowStd<S>>) -> R,
-        AllowStd<S>: Read + Write,
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        // Once the alert is sent, shutting down again would wait for the
+        // peer's close_notify with some backends, so only the flush is
+        // retried if the transport is not ready yet.
+        if !self.close_notify_sent {
+            match self.with_context(ctx, |s| s.shutdown()) {
+                Poll::Ready(Ok(())) => self.close_notify_sent = true,
+                res => return res,
+            }
+        }
+        self.with_context(ctx, |s| s.flush())
+    }
+
+    /// Returns whether the session ended without the peer sending a
+    /// close_notify alert.
+    fn is_truncated(&mut self) -> bool
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match self.inner.close_notify_received() {
+            Some(received) => !received,
+            // The backends stop reading at a close_notify alert, so reaching
+            // the end of the transport means it was never sent.
+            None => self.inner.get_ref().is_eof(),
+        }
+    }
+
+    fn poll_write_slices(
+        &mut self,
+        ctx: &mut Context<'_>,
+        slices: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
     {
-        self.0.get_mut().context = ctx as *mut _ as *mut ();
-        let g = Guard(self);
-        f(&mut (g.0).0)
+        let first = slices.iter().position(|s| !s.is_empty());
+        let slices = match first {
+            Some(i) => &slices[i..],
+            None => return Poll::Ready(Ok(0)),
+        };
+        if slices.len() == 1 || slices[0].len() >= MAX_RECORD_PLAINTEXT {
+            return self.with_context(ctx, |s| s.write(&slices[0]));
+        }
+
+        // The buffer is kept around so that a write which has to be retried
+        // passes the same memory to the backend again.
+        let mut coalesce = std::mem::take(&mut self.coalesce);
+        coalesce.clear();
+        for slice in slices {
+            let n = slice.len().min(MAX_RECORD_PLAINTEXT - coalesce.len());
+            coalesce.extend_from_slice(&slice[..n]);
+            if coalesce.len() == MAX_RECORD_PLAINTEXT {
+                break;
+            }
+        }
+        let res = self.with_context(ctx, |s| s.write(&coalesce));
+        self.coalesce = coalesce;
+        res
+    }
+
+    fn with_context<F, R>(&mut self, ctx: &mut Context<'_>, f: F) -> Poll<io::Result<R>>
+    where
+        F: FnOnce(&mut backend::Stream<AllowStd<S>>) -> io::Result<R>,
+        S: AsyncWrite + Unpin,
+    {
+        self.inner.get_mut().register(ctx);
+        let res = f(&mut self.inner);
+        // Send the records produced by `f` right away, as far as `S` allows.
+        // Whatever is left is sent by the next operation.
+        let flushed = self.inner.get_mut().flush_buf();
+        self.inner.get_mut().unregister();
+
+        match (res, flushed) {
+            (Err(ref e), Err(f))
+                if e.kind() == io::ErrorKind::WouldBlock
+                    && f.kind() != io::ErrorKind::WouldBlock =>
+            {
+                Poll::Ready(Err(f))
+            }
+            (res, _) => cvt(res),
+        }
     }
 
     /// Returns a shared reference to the inner stream.
@@ -170,7 +360,7 @@ impl<S> TlsStream<S> {
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
-        &self.0.get_ref().inner
+        self.inner.get_ref().get_ref()
     }
 
     /// Returns a mutable reference to the inner stream.
@@ -178,7 +368,150 @@ impl<S> TlsStream<S> {
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
-        &mut self.0.get_mut().inner
+        self.inner.get_mut().get_mut()
+    }
+
+    /// Closes the TLS session and returns the underlying stream.
+    ///
+    /// A close_notify alert is sent, and the returned future then waits for
+    /// the peer's close_notify in return, so that the stream is left right
+    /// after the end of the session. This allows protocols which upgrade a
+    /// connection to TLS to continue in plaintext afterwards, or the stream to
+    /// be used for another session. Unlike `shutdown`, the underlying stream
+    /// itself is not shut down.
+    ///
+    /// The future resolves to the stream, together with any bytes which were
+    /// already read from it after the peer's close_notify. These are the
+    /// first bytes the peer sent past the end of the session.
+    ///
+    /// Application data the peer sends before its close_notify is discarded,
+    /// so it should be read beforehand. If the peer closes the connection
+    /// without sending close_notify, the future fails with an `UnexpectedEof`
+    /// error.
+    pub fn into_inner(self) -> IntoInner<S> {
+        IntoInner {
+            stream: Some(self),
+            closed: false,
+        }
+    }
+
+    /// Splits this stream into independently owned read and write halves.
+    ///
+    /// The halves can be moved to separate tasks, allowing one task to read
+    /// while another one writes. Both halves still drive the same TLS session,
+    /// so only one of them makes progress at any given instant. The original
+    /// stream can be recovered with `ReadHalf::unsplit`.
+    pub fn into_split(self) -> (ReadHalf<S>, WriteHalf<S>) {
+        split::split(self)
+    }
+
+    /// Returns the certificate presented by the peer, if any.
+    ///
+    /// On a stream returned by `TlsConnector::connect` this is the server's
+    /// leaf certificate. On a stream returned by `TlsAcceptor::accept` this is
+    /// the client's certificate, which is only present if the acceptor was
+    /// configured to request one and the client supplied it.
+    pub fn peer_certificate(&self) -> Result<Option<Certificate>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.inner.peer_certificate()
+    }
+
+    /// Returns the certificate a client authenticated with, on a stream
+    /// returned by `TlsAcceptor::accept`.
+    ///
+    /// This is only present if the acceptor asked for a client certificate
+    /// with `TlsAcceptor::set_client_auth`, and the client supplied one. It
+    /// has then been verified against the acceptor's trusted roots, so its
+    /// subject identifies the client. Streams returned by
+    /// `TlsConnector::connect` always return `None`.
+    pub fn client_certificate(&self) -> Result<Option<Certificate>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.inner.client_certificate()
+    }
+
+    /// Returns the certificate chain presented by the peer, leaf first.
+    ///
+    /// Returns `None` with the `native-tls` backend, which does not expose the
+    /// certificates sent by the peer beyond the leaf; use `peer_certificate`
+    /// to inspect the leaf certificate there.
+    pub fn peer_certificate_chain(&self) -> Result<Option<Vec<Certificate>>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.inner.peer_certificate_chain()
+    }
+
+    /// Returns whether the handshake resumed a session established by an
+    /// earlier connection, rather than performing a full handshake.
+    ///
+    /// `native-tls` does not report this, and rustls servers only do for
+    /// TLS 1.3 sessions, so otherwise this always returns `false`.
+    pub fn session_resumed(&self) -> bool {
+        self.inner.session_resumed()
+    }
+
+    /// Returns the protocol selected through ALPN during the handshake, if
+    /// any, e.g. `b"h2"` or `b"http/1.1"`.
+    ///
+    /// With the `native-tls` backend this always returns `None` unless the
+    /// `alpn` feature is enabled. In any case both ends of the connection must
+    /// have been configured with a list of protocols.
+    pub fn negotiated_alpn(&self) -> Result<Option<Vec<u8>>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.inner.negotiated_alpn()
+    }
+
+    /// Returns the OpenSSL session of this stream, if it was negotiated by a
+    /// connector or acceptor created from an `openssl` type.
+    ///
+    /// This allows inspecting connection details which are not exposed by
+    /// this crate, such as the negotiated cipher or the peer's temporary key.
+    #[cfg(feature = "openssl")]
+    pub fn ssl(&self) -> Option<&openssl::ssl::SslRef> {
+        self.inner.ssl()
+    }
+
+    /// Returns the host name the client requested through SNI, if any.
+    ///
+    /// This is only known on streams returned by `TlsAcceptor::accept`, when
+    /// the acceptor was created with `TlsAcceptor::with_resolver` or uses the
+    /// rustls backend.
+    pub fn server_name(&self) -> Option<&str> {
+        self.server_name
+            .as_deref()
+            .or_else(|| self.inner.server_name())
+    }
+
+    /// Returns the JA3 fingerprint of the client, in hex.
+    ///
+    /// This is only known on streams returned by `TlsAcceptor::accept` when
+    /// client fingerprinting has been enabled with
+    /// `TlsAcceptor::set_fingerprint_clients`.
+    pub fn ja3(&self) -> Option<&str> {
+        self.fingerprint.as_ref().map(|f| &f.ja3[..])
+    }
+
+    /// Returns the JA4 fingerprint of the client.
+    ///
+    /// This is only known on streams returned by `TlsAcceptor::accept` when
+    /// client fingerprinting has been enabled with
+    /// `TlsAcceptor::set_fingerprint_clients`.
+    pub fn ja4(&self) -> Option<&str> {
+        self.fingerprint.as_ref().map(|f| &f.ja4[..])
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for TlsStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("TlsStream")
+            .field(self.inner.get_ref().get_ref())
+            .finish()
     }
 }
 
@@ -198,7 +531,18 @@ where
         ctx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        self.with_context(ctx, |s| cvt(s.read(buf)))
+        let res = self.with_context(ctx, |s| s.read(buf));
+        // Servers may send the sessions to resume after the handshake.
+        let this = &mut *self;
+        if let Some(ref mut sessions) = this.sessions {
+            this.inner.save_session(sessions);
+        }
+        if let Poll::Ready(Ok(0)) = res {
+            if !buf.is_empty() && !self.allow_truncation && self.is_truncated() {
+                return Poll::Ready(Err(truncated()));
+            }
+        }
+        res
     }
 }
 
@@ -211,45 +555,326 @@ where
         ctx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
-        self.with_context(ctx, |s| cvt(s.write(buf)))
+        self.with_context(ctx, |s| s.write(buf))
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        self.with_context(ctx, |s| cvt(s.flush()))
+        self.with_context(ctx, |s| s.flush())
+    }
+
+    /// Writes the chunks of `buf` as few TLS records as possible.
+    ///
+    /// Small chunks, such as a header followed by a body, are copied
+    /// together into records of up to 16 KiB instead of each being sent in a
+    /// record of its own.
+    fn poll_write_buf<B: Buf>(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>>
+    where
+        Self: Sized,
+    {
+        if !buf.has_remaining() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let mut slices = [IoSlice::new(&[]); MAX_WRITE_SLICES];
+        let cnt = buf.bytes_vectored(&mut slices);
+        let res = self.poll_write_slices(ctx, &slices[..cnt]);
+        if let Poll::Ready(Ok(n)) = res {
+            buf.advance(n);
+        }
+        res
     }
 
+    /// Sends a close_notify alert, flushes it and then shuts down the
+    /// transport.
     fn poll_shutdown(mut self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        match self.with_context(ctx, |s| s.shutdown()) {
-            Ok(()) => Poll::Ready(Ok(())),
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Poll::Pending,
-            Err(e) => Poll::Ready(Err(e)),
+        if self.shutdown {
+            return Poll::Ready(Ok(()));
         }
+        match self.poll_close_notify(ctx) {
+            Poll::Ready(Ok(())) => {}
+            res => return res,
+        }
+        let res = match Pin::new(self.inner.get_mut().get_mut()).poll_shutdown(ctx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => return Poll::Pending,
+        };
+        match res {
+            // The peer may have closed the transport already.
+            Err(ref e) if e.kind() == io::ErrorKind::NotConnected => {}
+            Err(e) => return Poll::Ready(Err(e)),
+            Ok(()) => {}
+        }
+        self.shutdown = true;
+        Poll::Ready(Ok(()))
     }
 }
 
-async fn handshake<F, S>(f: F, stream: S) -> Result<TlsStream<S>, Error>
-where
-    F: FnOnce(
-            AllowStd<S>,
-        ) -> Result<native_tls::TlsStream<AllowStd<S>>, HandshakeError<AllowStd<S>>>
-        + Unpin,
-    S: AsyncRead + AsyncWrite + Unpin,
-{
-    let start = StartedHandshakeFuture(Some(StartedHandshakeFutureInner { f, stream }));
+impl<S> Handshake<S> {
+    fn new(
+        start: Start,
+        stream: S,
+        prefix: Vec<u8>,
+        timeout: Option<Duration>,
+        config: StreamConfig,
+    ) -> Handshake<S> {
+        let stream = AllowStd::new(stream, prefix, config.buffer_sizes);
+        Handshake {
+            state: HandshakeState::Start(StartedHandshakeFuture::new(start, stream)),
+            timeout,
+            config,
+            delay: None,
+            server_name: None,
+            fingerprint: None,
+            checks: None,
+            sessions: None,
+        }
+    }
+
+    /// Creates a handshake which reads the ClientHello before the backend
+    /// acceptor is picked by `acceptor`, and its hook is run.
+    fn with_client_hello(
+        acceptor: TlsAcceptor,
+        stream: S,
+        prefix: Vec<u8>,
+        timeout: Option<Duration>,
+        config: StreamConfig,
+    ) -> Handshake<S> {
+        let read = ReadClientHello::new(stream, prefix);
+        Handshake {
+            state: HandshakeState::ClientHello(read, acceptor),
+            timeout,
+            config,
+            delay: None,
+            server_name: None,
+            fingerprint: None,
+            checks: None,
+            sessions: None,
+        }
+    }
+
+    fn poll_timeout(&mut self, cx: &mut Context<'_>) -> Poll<Result<TlsStream<S>, Error>> {
+        // The deadline is armed on the first poll which could not complete
+        // the handshake, as creating a `Delay` requires a running timer.
+        if let Some(timeout) = self.timeout {
+            let delay = self
+                .delay
+                .get_or_insert_with(|| tokio::time::delay_for(timeout));
+            if Pin::new(delay).poll(cx).is_ready() {
+                // Dropping the state drops the half-open transport with it.
+                self.state = HandshakeState::Done;
+                return Poll::Ready(Err(Error::timeout()));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Handshake<S> {
+    /// Applies the settings of the connector or acceptor to a stream whose
+    /// handshake has completed, and checks the server's certificate chain.
+    fn finish(&mut self, mut s: TlsStream<S>) -> Result<TlsStream<S>, Error> {
+        s.server_name = self.server_name.take();
+        s.fingerprint = self.fingerprint.take();
+        s.allow_truncation = self.config.allow_truncation;
+        if let Some(checks) = self.checks.take() {
+            // native-tls only exposes the leaf certificate.
+            let chain = match s.peer_certificate_chain()? {
+                Some(chain) => chain,
+                None => s.peer_certificate()?.into_iter().collect(),
+            };
+            checks.check(&chain)?;
+        }
+        if let Some(mut sessions) = self.sessions.take() {
+            if s.inner.session_resumed() {
+                sessions.cache.record_resumed();
+            }
+            s.inner.save_session(&mut sessions);
+            s.sessions = Some(sessions);
+        }
+        Ok(s)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Handshake<S> {
+    type Output = Result<TlsStream<S>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if let HandshakeState::ClientHello(ref mut f, ref acceptor) = this.state {
+            let (stream, prefix, hello) = match Pin::new(f).poll(cx) {
+                Poll::Ready(Ok(res)) => res,
+                Poll::Ready(Err(e)) => {
+                    this.state = HandshakeState::Done;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return this.poll_timeout(cx),
+            };
+            // Malformed messages are left to the backend to reject.
+            let inspected = match (&acceptor.client_hello_hook, &hello) {
+                (Some(hook), Some(hello)) => hook.inspect(hello),
+                _ => Ok(()),
+            };
+            match inspected.and_then(|()| sni::resolve(acceptor, hello.as_ref())) {
+                Ok(acceptor) => {
+                    let start = Start::Accept(acceptor);
+                    let stream = AllowStd::new(stream, prefix, this.config.buffer_sizes);
+                    let start = StartedHandshakeFuture::new(start, stream);
+                    this.state = HandshakeState::Start(start);
+                    if this.config.fingerprint_clients {
+                        this.fingerprint = hello.as_ref().map(Fingerprint::new);
+                    }
+                    this.server_name = hello.and_then(|h| h.server_name().map(str::to_owned));
+                }
+                Err(e) => {
+                    this.state = HandshakeState::Done;
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+
+        if let HandshakeState::Start(ref mut f) = this.state {
+            match Pin::new(f).poll(cx) {
+                Poll::Ready(Ok(StartedHandshake::Done(s))) => {
+                    this.state = HandshakeState::Done;
+                    return Poll::Ready(this.finish(s));
+                }
+                Poll::Ready(Ok(StartedHandshake::Mid(s))) => {
+                    this.state = HandshakeState::Mid(MidHandshake(Some(s)));
+                }
+                Poll::Ready(Err(e)) => {
+                    this.state = HandshakeState::Done;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => unreachable!(),
+            }
+        }
+
+        match this.state {
+            HandshakeState::Mid(ref mut f) => {
+                if let Poll::Ready(res) = Pin::new(f).poll(cx) {
+                    this.state = HandshakeState::Done;
+                    return Poll::Ready(res.and_then(|s| this.finish(s)));
+                }
+            }
+            HandshakeState::Done => panic!("future polled after completion"),
+            HandshakeState::ClientHello(..) | HandshakeState::Start(_) => unreachable!(),
+        }
+
+        this.poll_timeout(cx)
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Connect<S> {
+    type Output = Result<TlsStream<S>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<S> fmt::Debug for Connect<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connect").finish()
+    }
+}
+
+impl<S> Accept<S> {
+    /// Makes the handshake time out at the deadline of `delay`, which was
+    /// armed while reading from the same connection before the handshake.
+    pub(crate) fn with_delay(mut self, delay: Option<Delay>) -> Accept<S> {
+        if delay.is_some() {
+            self.0.delay = delay;
+        }
+        self
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for Accept<S> {
+    type Output = Result<TlsStream<S>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx)
+    }
+}
+
+impl<S> fmt::Debug for Accept<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Accept").finish()
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for IntoInner<S> {
+    type Output = Result<(S, Vec<u8>), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let stream = this
+            .stream
+            .as_mut()
+            .expect("future polled after completion");
+
+        if !this.closed {
+            match stream.poll_close_notify(cx) {
+                Poll::Ready(Ok(())) => this.closed = true,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        // Drain the session up to the peer's close_notify.
+        let mut buf = [0; 1024];
+        loop {
+            match stream.with_context(cx, |s| s.read(&mut buf)) {
+                Poll::Ready(Ok(0)) => break,
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e.into())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        if stream.is_truncated() {
+            return Poll::Ready(Err(truncated().into()));
+        }
 
-    match start.await {
-        Err(e) => Err(e),
-        Ok(StartedHandshake::Done(s)) => Ok(s),
-        Ok(StartedHandshake::Mid(s)) => MidHandshake(Some(s)).await,
+        let mut stream = this.stream.take().unwrap();
+        Poll::Ready(Ok(stream.inner.get_mut().take_inner()))
     }
 }
 
-impl<F, S> Future for StartedHandshakeFuture<F, S>
+impl<S> fmt::Debug for IntoInner<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IntoInner").finish()
+    }
+}
+
+impl Start {
+    fn start<S>(
+        self,
+        stream: AllowStd<S>,
+    ) -> Result<backend::Stream<AllowStd<S>>, HandshakeError<AllowStd<S>>>
+    where
+        AllowStd<S>: Read + Write,
+    {
+        match self {
+            Start::Connect(connector, domain, addr) => connector.connect(&domain, addr, stream),
+            Start::Accept(acceptor) => acceptor.accept(stream),
+        }
+    }
+}
+
+impl<S> StartedHandshakeFuture<S> {
+    fn new(start: Start, stream: AllowStd<S>) -> StartedHandshakeFuture<S> {
+        StartedHandshakeFuture(Some(StartedHandshakeFutureInner { start, stream }))
+    }
+}
+
+impl<S> Future for StartedHandshakeFuture<S>
 where
-    F: FnOnce(
-            AllowStd<S>,
-        ) -> Result<native_tls::TlsStream<AllowStd<S>>, HandshakeError<AllowStd<S>>>
-        + Unpin,
     S: Unpin,
     AllowStd<S>: Read + Write,
 {
@@ -260,18 +885,16 @@ where
         ctx: &mut Context<'_>,
     ) -> Poll<Result<StartedHandshake<S>, Error>> {
         let inner = self.0.take().expect("future polled after completion");
-        let stream = AllowStd {
-            inner: inner.stream,
-            context: ctx as *mut _ as *mut (),
-        };
+        let mut stream = inner.stream;
+        stream.register(ctx);
 
-        match (inner.f)(stream) {
+        match inner.start.start(stream) {
             Ok(mut s) => {
-                s.get_mut().context = null_mut();
-                Poll::Ready(Ok(StartedHandshake::Done(TlsStream(s))))
+                s.get_mut().unregister();
+                Poll::Ready(Ok(StartedHandshake::Done(TlsStream::new(s))))
             }
             Err(HandshakeError::WouldBlock(mut s)) => {
-                s.get_mut().context = null_mut();
+                s.get_mut().unregister();
                 Poll::Ready(Ok(StartedHandshake::Mid(s)))
             }
             Err(HandshakeError::Failure(e)) => Poll::Ready(Err(e)),
@@ -280,23 +903,258 @@ where
 }
 
 impl TlsConnector {
+    fn new(inner: backend::Connector) -> TlsConnector {
+        TlsConnector {
+            inner,
+            handshake_timeout: None,
+            config: StreamConfig::default(),
+            verifier: None,
+            pins: None,
+        }
+    }
+
     /// Connects the provided stream with this connector, assuming the provided
     /// domain.
     ///
     /// This function will internally call `TlsConnector::connect` to connect
     /// the stream and returns a future representing the resolution of the
     /// connection operation. The returned future will resolve to either
-    /// `TlsStream<S>` or `Error` depending if it's successful or not.
+    /// `TlsStream<S>` or `Error` depending if it's successful or not. It holds
+    /// its own copy of this connector and the domain, so it does not borrow
+    /// from either.
     ///
     /// This is typically used for clients who have already established, for
     /// example, a TCP connection to a remote server. That stream is then
     /// provided here to perform the client half of a connection to a
     /// TLS-powered server.
-    pub async fn connect<S>(&self, domain: &str, stream: S) -> Result<TlsStream<S>, Error>
+    ///
+    /// If a handshake timeout has been configured with
+    /// `set_handshake_timeout` and the handshake does not complete in time,
+    /// the stream is dropped and an error for which `Error::is_timeout`
+    /// returns `true` is returned.
+    pub fn connect<S>(&self, domain: &str, stream: S) -> Connect<S>
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
-        handshake(move |s| self.0.connect(domain, s), stream).await
+        self.connect_with_prefix(domain, stream, Vec::new())
+    }
+
+    /// Connects the provided stream like `connect`, given bytes which have
+    /// already been read from it.
+    ///
+    /// `prefix` is processed as if it was the first data received from
+    /// `stream`. This is needed when upgrading a plaintext connection to TLS
+    /// after reading from it through a buffer, such as a `BufReader`, which
+    /// may hold the beginning of the server's handshake already. The
+    /// `starttls` module provides such upgrades for common protocols.
+    pub fn connect_with_prefix<S>(&self, domain: &str, stream: S, prefix: Vec<u8>) -> Connect<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.start_connect(domain, None, stream, prefix)
+    }
+
+    /// Connects the provided stream like `connect`, given the address of the
+    /// server it is connected to.
+    ///
+    /// With a session cache enabled through `set_session_cache_size`,
+    /// sessions established this way are cached for the address as well as
+    /// the domain. This keeps the sessions of several servers behind the
+    /// same name apart, which is needed when they do not share their session
+    /// state and would otherwise turn down each other's sessions.
+    pub fn connect_with_addr<S>(&self, domain: &str, addr: SocketAddr, stream: S) -> Connect<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.start_connect(domain, Some(addr), stream, Vec::new())
+    }
+
+    fn start_connect<S>(
+        &self,
+        domain: &str,
+        addr: Option<SocketAddr>,
+        stream: S,
+        prefix: Vec<u8>,
+    ) -> Connect<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let start = Start::Connect(self.inner.clone(), domain.to_owned(), addr);
+        let mut handshake =
+            Handshake::new(start, stream, prefix, self.handshake_timeout, self.config);
+        handshake.sessions = self.inner.session_cache().map(|cache| {
+            Box::new(ClientSessions {
+                cache: cache.clone(),
+                key: session::key(domain, addr),
+                seen: Vec::new(),
+            })
+        });
+        if self.verifier.is_some() || self.pins.is_some() {
+            handshake.checks = Some(ServerChecks {
+                domain: domain.to_owned(),
+                verifier: self.verifier.clone(),
+                pins: self.pins.clone(),
+            });
+        }
+        Connect(handshake)
+    }
+
+    /// Sets the maximum amount of time a handshake started by `connect` may
+    /// take before it is aborted.
+    ///
+    /// Defaults to `None`, meaning that handshakes may take arbitrarily long.
+    /// Handshake timeouts require the Tokio timer to be running.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Returns the handshake timeout configured with `set_handshake_timeout`.
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
+    /// Sets the size of the buffer ciphertext is read into from the
+    /// transport.
+    ///
+    /// Reading in large chunks lets several TLS records be received with a
+    /// single read from the transport. Defaults to 32 KiB. A size of 0
+    /// disables read buffering, so that the TLS session reads from the
+    /// transport directly.
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        self.config.buffer_sizes.read = size;
+    }
+
+    /// Returns the read buffer size configured with `set_read_buffer_size`.
+    pub fn read_buffer_size(&self) -> usize {
+        self.config.buffer_sizes.read
+    }
+
+    /// Sets the size of the buffer ciphertext is collected in before it is
+    /// written to the transport.
+    ///
+    /// Records produced by one operation on a `TlsStream` are collected and
+    /// written to the transport together, once the operation completes.
+    /// Defaults to 32 KiB. A size of 0 disables write buffering, so that
+    /// each record is written to the transport as soon as it is produced.
+    pub fn set_write_buffer_size(&mut self, size: usize) {
+        self.config.buffer_sizes.write = size;
+    }
+
+    /// Returns the write buffer size configured with `set_write_buffer_size`.
+    pub fn write_buffer_size(&self) -> usize {
+        self.config.buffer_sizes.write
+    }
+
+    /// Sets whether streams established by `connect` treat a connection closed
+    /// without a close_notify alert as a regular end of file.
+    ///
+    /// By default, reading from a stream whose peer closed the transport
+    /// without sending close_notify fails with an `UnexpectedEof` error, as
+    /// an attacker may have cut the connection short to truncate the data.
+    /// Protocols which delimit their messages themselves, such as HTTP with a
+    /// `Content-Length`, can safely allow truncation to interoperate with
+    /// peers that do not close the session properly.
+    pub fn set_allow_truncation(&mut self, allow: bool) {
+        self.config.allow_truncation = allow;
+    }
+
+    /// Returns whether truncation is allowed, as configured with
+    /// `set_allow_truncation`.
+    pub fn allow_truncation(&self) -> bool {
+        self.config.allow_truncation
+    }
+
+    /// Sets a verifier which decides whether to trust the certificate chain
+    /// presented by a server, in place of the backend's own verification.
+    ///
+    /// Neither the chain nor the domain are checked by the backend once a
+    /// verifier is set, so it is up to the verifier to do so, e.g. by
+    /// comparing the leaf certificate's public key against a pinned one, or
+    /// its URI subject alternative name against an expected SPIFFE ID. The
+    /// verifier runs once the handshake has completed, before the stream is
+    /// returned. A rejected connection is closed before any data is sent
+    /// over it, and `connect` fails with an error whose `kind` is
+    /// `ErrorKind::InvalidCertificate` and which includes the reason.
+    ///
+    /// The connector stops sharing its session cache with other clones, and
+    /// starts with an empty one of the same size, so that connectors which
+    /// verify servers differently never resume each other's sessions. For the
+    /// same reason, a connector created from a `rustls::ClientConfig` stops
+    /// resuming sessions unless `set_session_cache_size` is called.
+    ///
+    /// Returns an error if the connector was created from a
+    /// `native_tls::TlsConnector`, whose verification cannot be turned off
+    /// once it is built.
+    pub fn set_certificate_verifier<V>(&mut self, verifier: V) -> Result<(), Error>
+    where
+        V: CertificateVerifier + 'static,
+    {
+        self.inner = self.inner.without_verification()?;
+        self.verifier = Some(Arc::new(verifier));
+        Ok(())
+    }
+
+    /// Sets the public keys which the certificate chain presented by a server
+    /// must contain one of.
+    ///
+    /// Pins are checked once the handshake has completed, after the chain
+    /// has been validated by the backend, or by the verifier set with
+    /// `set_certificate_verifier`. A chain matching none of them is rejected
+    /// like by a verifier, unless the set is in report-only mode.
+    ///
+    /// `native-tls` does not expose the intermediate certificates sent by the
+    /// server, so with that backend only the leaf certificate's key can be
+    /// pinned. Defaults to `None`, meaning that no keys are pinned.
+    pub fn set_pins(&mut self, pins: Option<PinSet>) {
+        self.pins = pins.map(Arc::new);
+    }
+
+    /// Returns the pins configured with `set_pins`.
+    pub fn pins(&self) -> Option<&PinSet> {
+        self.pins.as_deref()
+    }
+
+    /// Sets how many sessions established by `connect` are cached, so that
+    /// later connections to the same server can resume them with an
+    /// abbreviated handshake.
+    ///
+    /// Sessions are cached for the domain passed to `connect`, along with the
+    /// server's address when connecting with `connect_with_addr`, and the
+    /// oldest ones are evicted once the cache is full. The cache is shared
+    /// with the clones of this connector made after calling this method, and
+    /// replaces the session storage of a `rustls::ClientConfig`. A size of 0
+    /// disables session resumption. Defaults to 0, except for connectors
+    /// created from a `rustls::ClientConfig`, which keep using its session
+    /// storage until this is called.
+    ///
+    /// Returns an error if a session cache is requested from a connector
+    /// created from a `native_tls::TlsConnector`, which does not support it.
+    pub fn set_session_cache_size(&mut self, size: usize) -> Result<(), Error> {
+        let cache = match size {
+            0 => None,
+            size => Some(Arc::new(SessionCache::new(size))),
+        };
+        self.inner = self.inner.with_session_cache(cache)?;
+        Ok(())
+    }
+
+    /// Returns the size of the session cache, as configured with
+    /// `set_session_cache_size`.
+    pub fn session_cache_size(&self) -> usize {
+        self.inner
+            .session_cache()
+            .map_or(0, |cache| cache.capacity())
+    }
+
+    /// Returns how often the session cache had a session to offer, and how
+    /// often servers resumed it.
+    ///
+    /// The counters are shared by all the clones of this connector using the
+    /// same cache, and are all zero if no cache is set.
+    pub fn session_cache_stats(&self) -> SessionCacheStats {
+        self.inner
+            .session_cache()
+            .map_or_else(SessionCacheStats::default, |cache| cache.stats())
     }
 }
 
@@ -308,26 +1166,309 @@ impl fmt::Debug for TlsConnector {
 
 impl From<native_tls::TlsConnector> for TlsConnector {
     fn from(inner: native_tls::TlsConnector) -> TlsConnector {
-        TlsConnector(inner)
+        TlsConnector::new(backend::Connector::NativeTls(inner))
+    }
+}
+
+/// Uses OpenSSL directly for connections made through this connector.
+#[cfg(feature = "openssl")]
+impl From<openssl::ssl::SslConnector> for TlsConnector {
+    fn from(inner: openssl::ssl::SslConnector) -> TlsConnector {
+        TlsConnector::new(backend::Connector::OpenSsl(inner, true, None))
+    }
+}
+
+/// Uses rustls for connections made through this connector.
+///
+/// The domain passed to `TlsConnector::connect` must be a valid DNS name.
+#[cfg(feature = "rustls")]
+impl From<std::sync::Arc<rustls::ClientConfig>> for TlsConnector {
+    fn from(inner: std::sync::Arc<rustls::ClientConfig>) -> TlsConnector {
+        TlsConnector::new(backend::Connector::Rustls(inner, None))
     }
 }
 
 impl TlsAcceptor {
+    fn new(inner: backend::Acceptor) -> TlsAcceptor {
+        TlsAcceptor {
+            inner: AcceptorInner::Backend(inner),
+            handshake_timeout: None,
+            config: StreamConfig::default(),
+            client_hello_hook: None,
+        }
+    }
+
+    /// Creates an acceptor which picks the acceptor to use for each
+    /// connection based on the server name requested by the client.
+    ///
+    /// The client's ClientHello is read and passed on to `resolver`, which
+    /// returns the acceptor that completes the handshake, e.g. one with a
+    /// certificate for the requested name. The handshake timeout, buffer
+    /// sizes, truncation setting and ClientHello hook of that acceptor are
+    /// ignored in favor of the ones set on the returned acceptor.
+    /// Connections for which `resolver` returns `None` are rejected.
+    ///
+    /// The requested name is available on the resulting stream through
+    /// `TlsStream::server_name`.
+    pub fn with_resolver<R>(resolver: R) -> TlsAcceptor
+    where
+        R: ServerNameResolver + 'static,
+    {
+        TlsAcceptor {
+            inner: AcceptorInner::Resolver(Arc::new(resolver)),
+            handshake_timeout: None,
+            config: StreamConfig::default(),
+            client_hello_hook: None,
+        }
+    }
+
     /// Accepts a new client connection with the provided stream.
     ///
     /// This function will internally call `TlsAcceptor::accept` to connect
     /// the stream and returns a future representing the resolution of the
     /// connection operation. The returned future will resolve to either
-    /// `TlsStream<S>` or `Error` depending if it's successful or not.
+    /// `TlsStream<S>` or `Error` depending if it's successful or not. It holds
+    /// its own copy of this acceptor, so it does not borrow from it.
     ///
     /// This is typically used after a new socket has been accepted from a
     /// `TcpListener`. That socket is then passed to this function to perform
     /// the server half of accepting a client connection.
-    pub async fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, Error>
+    ///
+    /// If a handshake timeout has been configured with
+    /// `set_handshake_timeout` and the client does not complete the handshake
+    /// in time, the stream is dropped and an error for which
+    /// `Error::is_timeout` returns `true` is returned. This prevents stalled
+    /// clients from holding on to server resources indefinitely.
+    pub fn accept<S>(&self, stream: S) -> Accept<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        self.accept_with_prefix(stream, Vec::new())
+    }
+
+    /// Accepts a client connection with the provided stream like `accept`,
+    /// given bytes which have already been read from it.
+    ///
+    /// `prefix` is processed as if it was the first data received from
+    /// `stream`. This is needed when upgrading a plaintext connection to TLS
+    /// after reading from it through a buffer, such as a `BufReader`, which
+    /// may hold the beginning of the client's handshake already, e.g. because
+    /// the client sent it right behind its STARTTLS command. The `starttls`
+    /// module provides such upgrades for common protocols.
+    pub fn accept_with_prefix<S>(&self, stream: S, prefix: Vec<u8>) -> Accept<S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let timeout = self.handshake_timeout;
+        let config = self.config;
+        match self.inner {
+            AcceptorInner::Backend(ref inner)
+                if self.client_hello_hook.is_none() && !config.fingerprint_clients =>
+            {
+                Accept(Handshake::new(
+                    Start::Accept(inner.clone()),
+                    stream,
+                    prefix,
+                    timeout,
+                    config,
+                ))
+            }
+            AcceptorInner::Backend(_) | AcceptorInner::Resolver(_) => Accept(
+                Handshake::with_client_hello(self.clone(), stream, prefix, timeout, config),
+            ),
+            AcceptorInner::Reloadable(ref current) => {
+                let mut acceptor = current.read().unwrap().clone();
+                acceptor.handshake_timeout = timeout;
+                acceptor.config = config;
+                acceptor.client_hello_hook = self.client_hello_hook.clone();
+                acceptor.accept_with_prefix(stream, prefix)
+            }
+        }
+    }
+
+    /// Accepts a client connection which may either be a TLS connection or a
+    /// plaintext one, allowing both to be served on the same port.
+    ///
+    /// The first bytes sent by the client are read to tell whether it is
+    /// starting a TLS handshake. If it is, the handshake is performed as by
+    /// `accept` and a `MaybeTlsStream::Tls` is returned. Otherwise the stream
+    /// is returned as a `MaybeTlsStream::Plaintext`, which replays the bytes
+    /// that were read before returning the rest of the stream.
+    ///
+    /// This only works for protocols in which the client sends data first,
+    /// such as HTTP. The handshake timeout also limits the time the client
+    /// may take to send its first bytes.
+    pub fn accept_or_plaintext<S>(&self, stream: S) -> AcceptOrPlaintext<S>
     where
         S: AsyncRead + AsyncWrite + Unpin,
     {
-        handshake(move |s| self.0.accept(s), stream).await
+        sniff::AcceptOrPlaintext::new(self.clone(), stream)
+    }
+
+    /// Makes this acceptor reloadable, returning it along with a handle for
+    /// replacing the acceptor used for new connections.
+    ///
+    /// Connections accepted through the returned acceptor, or any of its
+    /// clones, are handled by whichever acceptor was most recently installed
+    /// through the handle. Handshakes in progress and established streams are
+    /// not affected by a reload, so certificates can be rotated without
+    /// dropping connections. The handshake timeout, buffer sizes, truncation
+    /// setting and ClientHello hook of the returned acceptor apply to all
+    /// connections, regardless of the acceptors installed later.
+    ///
+    /// `ReloadHandle::watch_identity` reloads the acceptor automatically when
+    /// the certificate files change on disk.
+    pub fn reloadable(self) -> (TlsAcceptor, ReloadHandle) {
+        let handshake_timeout = self.handshake_timeout;
+        let config = self.config;
+        let client_hello_hook = self.client_hello_hook.clone();
+        let handle = reload::new(self);
+        let acceptor = TlsAcceptor {
+            inner: AcceptorInner::Reloadable(handle.shared()),
+            handshake_timeout,
+            config,
+            client_hello_hook,
+        };
+        (acceptor, handle)
+    }
+
+    /// Sets the maximum amount of time a handshake started by `accept` may
+    /// take before it is aborted.
+    ///
+    /// Defaults to `None`, meaning that handshakes may take arbitrarily long.
+    /// Handshake timeouts require the Tokio timer to be running.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Returns the handshake timeout configured with `set_handshake_timeout`.
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
+    /// Sets the size of the buffer ciphertext is read into from the
+    /// transport, see `TlsConnector::set_read_buffer_size`.
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        self.config.buffer_sizes.read = size;
+    }
+
+    /// Returns the read buffer size configured with `set_read_buffer_size`.
+    pub fn read_buffer_size(&self) -> usize {
+        self.config.buffer_sizes.read
+    }
+
+    /// Sets the size of the buffer ciphertext is collected in before it is
+    /// written to the transport, see `TlsConnector::set_write_buffer_size`.
+    pub fn set_write_buffer_size(&mut self, size: usize) {
+        self.config.buffer_sizes.write = size;
+    }
+
+    /// Returns the write buffer size configured with `set_write_buffer_size`.
+    pub fn write_buffer_size(&self) -> usize {
+        self.config.buffer_sizes.write
+    }
+
+    /// Sets whether streams established by `accept` treat a connection closed
+    /// without a close_notify alert as a regular end of file, see
+    /// `TlsConnector::set_allow_truncation`.
+    pub fn set_allow_truncation(&mut self, allow: bool) {
+        self.config.allow_truncation = allow;
+    }
+
+    /// Returns whether truncation is allowed, as configured with
+    /// `set_allow_truncation`.
+    pub fn allow_truncation(&self) -> bool {
+        self.config.allow_truncation
+    }
+
+    /// Sets a hook which inspects the ClientHello of each connection before
+    /// the handshake proceeds, replacing any previously set hook.
+    ///
+    /// The ClientHello is read from the client and parsed into a
+    /// `ClientHello`, which `hook` uses to decide whether to continue, e.g.
+    /// based on the offered protocol versions or cipher suites, or just to
+    /// log it. If the hook returns an error, the handshake is aborted with
+    /// it. Otherwise the bytes read are replayed to the TLS backend, which
+    /// performs the handshake as usual. Messages which cannot be parsed are
+    /// not passed to the hook, and are rejected by the backend instead.
+    ///
+    /// To pick the acceptor for a connection based on its ClientHello,
+    /// implement `ServerNameResolver::resolve_client_hello` instead.
+    pub fn set_client_hello_hook<H>(&mut self, hook: H)
+    where
+        H: ClientHelloHook + 'static,
+    {
+        self.client_hello_hook = Some(Arc::new(hook));
+    }
+
+    /// Sets whether clients are asked to authenticate with a certificate,
+    /// and the roots their certificates are verified against.
+    ///
+    /// The certificate of an authenticated client is available through
+    /// `TlsStream::client_certificate`. This replaces any client
+    /// authentication configured on the `openssl` or `rustls` type the
+    /// acceptor was created from.
+    ///
+    /// Returns an error if a root is not a valid certificate, if client
+    /// authentication is requested from an acceptor created from a
+    /// `native_tls::TlsAcceptor`, which does not support it, or if this
+    /// acceptor was created with `with_resolver` or `reloadable`, in which
+    /// case it must be set on the underlying acceptors instead.
+    pub fn set_client_auth(&mut self, auth: ClientAuth) -> Result<(), Error> {
+        match self.inner {
+            AcceptorInner::Backend(ref mut inner) => {
+                *inner = inner.with_client_auth(&auth)?;
+                Ok(())
+            }
+            AcceptorInner::Resolver(_) | AcceptorInner::Reloadable(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "client authentication must be set on the acceptors connections are handed to",
+            )
+            .into()),
+        }
+    }
+
+    /// Sets how clients may resume the sessions of earlier connections,
+    /// replacing the session storage and ticketer of a
+    /// `rustls::ServerConfig`.
+    ///
+    /// Whether each accepted stream resumed a session is reported by
+    /// `TlsStream::session_resumed`.
+    ///
+    /// Returns an error if the acceptor was created from a
+    /// `native_tls::TlsAcceptor`, which does not support this, or from an
+    /// `openssl::ssl::SslAcceptor`, whose session cache and tickets are
+    /// configured on the `SslAcceptorBuilder` instead. As with
+    /// `set_client_auth`, an error is also returned if this acceptor was
+    /// created with `with_resolver` or `reloadable`.
+    pub fn set_session_resumption(&mut self, resumption: SessionResumption) -> Result<(), Error> {
+        match self.inner {
+            AcceptorInner::Backend(ref mut inner) => {
+                *inner = inner.with_session_resumption(&resumption)?;
+                Ok(())
+            }
+            AcceptorInner::Resolver(_) | AcceptorInner::Reloadable(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "session resumption must be set on the acceptors connections are handed to",
+            )
+            .into()),
+        }
+    }
+
+    /// Sets whether the JA3 and JA4 fingerprints of clients are computed
+    /// during `accept`.
+    ///
+    /// When enabled, the ClientHello is read and fingerprinted before it is
+    /// replayed to the TLS backend, and the fingerprints are available
+    /// through `TlsStream::ja3` and `TlsStream::ja4`. Defaults to `false`.
+    pub fn set_fingerprint_clients(&mut self, enabled: bool) {
+        self.config.fingerprint_clients = enabled;
+    }
+
+    /// Returns whether clients are fingerprinted, as configured with
+    /// `set_fingerprint_clients`.
+    pub fn fingerprint_clients(&self) -> bool {
+        self.config.fingerprint_clients
     }
 }
 
@@ -339,7 +1480,23 @@ impl fmt::Debug for TlsAcceptor {
 
 impl From<native_tls::TlsAcceptor> for TlsAcceptor {
     fn from(inner: native_tls::TlsAcceptor) -> TlsAcceptor {
-        TlsAcceptor(inner)
+        TlsAcceptor::new(backend::Acceptor::NativeTls(inner))
+    }
+}
+
+/// Uses OpenSSL directly for connections accepted through this acceptor.
+#[cfg(feature = "openssl")]
+impl From<openssl::ssl::SslAcceptor> for TlsAcceptor {
+    fn from(inner: openssl::ssl::SslAcceptor) -> TlsAcceptor {
+        TlsAcceptor::new(backend::Acceptor::OpenSsl(inner, None))
+    }
+}
+
+/// Uses rustls for connections accepted through this acceptor.
+#[cfg(feature = "rustls")]
+impl From<std::sync::Arc<rustls::ServerConfig>> for TlsAcceptor {
+    fn from(inner: std::sync::Arc<rustls::ServerConfig>) -> TlsAcceptor {
+        TlsAcceptor::new(backend::Acceptor::Rustls(inner))
     }
 }
 
@@ -350,12 +1507,15 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Future for MidHandshake<S> {
         let mut_self = self.get_mut();
         let mut s = mut_self.0.take().expect("future polled after completion");
 
-        s.get_mut().context = cx as *mut _ as *mut ();
+        s.get_mut().register(cx);
         match s.handshake() {
-            Ok(stream) => Poll::Ready(Ok(TlsStream(stream))),
+            Ok(mut stream) => {
+                stream.get_mut().unregister();
+                Poll::Ready(Ok(TlsStream::new(stream)))
+            }
             Err(HandshakeError::Failure(e)) => Poll::Ready(Err(e)),
             Err(HandshakeError::WouldBlock(mut s)) => {
-                s.get_mut().context = null_mut();
+                s.get_mut().unregister();
                 mut_self.0 = Some(s);
                 Poll::Pending
             }
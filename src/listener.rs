@@ -0,0 +1,154 @@
+use crate::{Accept, Error, TlsAcceptor, TlsStream};
+
+use futures_util::stream::FuturesUnordered;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::stream::Stream;
+
+use std::fmt;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const DEFAULT_MAX_HANDSHAKES: usize = 64;
+
+/// A stream of TLS connections accepted from a stream of incoming transports.
+///
+/// `TlsListener` takes the connections yielded by a stream such as
+/// `tokio::net::TcpListener::incoming`, performs the server half of the TLS
+/// handshake on each of them with a `TlsAcceptor`, and yields the resulting
+/// `TlsStream`s in the order their handshakes complete.
+///
+/// Handshakes run concurrently, up to the limit set with
+/// `set_max_handshakes`. Once that many are in flight, no further connections
+/// are taken from the incoming stream until one of them completes.
+///
+/// A failed handshake, or an error from the incoming stream, is yielded as an
+/// `Err` item and does not end the stream, so that a single misbehaving client
+/// cannot bring down the listener. The stream ends once the incoming stream
+/// has ended and all pending handshakes have completed.
+///
+/// # Examples
+///
+/// ```no_run
+/// use tokio::net::TcpListener;
+/// use tokio::stream::StreamExt;
+/// use tokio_tls::{TlsAcceptor, TlsListener};
+///
+/// # async fn run(acceptor: TlsAcceptor) -> std::io::Result<()> {
+/// let mut tcp = TcpListener::bind("127.0.0.1:8443").await?;
+/// let mut listener = TlsListener::new(acceptor, tcp.incoming());
+///
+/// while let Some(stream) = listener.next().await {
+///     match stream {
+///         Ok(stream) => {
+///             tokio::spawn(async move {
+///                 // Serve the connection.
+///                 drop(stream);
+///             });
+///         }
+///         Err(e) => eprintln!("failed to accept connection: {}", e),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct TlsListener<I, S> {
+    incoming: Option<I>,
+    acceptor: TlsAcceptor,
+    /// Only the handshakes which have been woken are polled.
+    handshakes: FuturesUnordered<Accept<S>>,
+    max_handshakes: usize,
+}
+
+impl<I, S> TlsListener<I, S> {
+    /// Creates a listener accepting TLS connections with `acceptor` from the
+    /// transports yielded by `incoming`.
+    ///
+    /// The acceptor's handshake timeout applies to every handshake, which is
+    /// recommended so that stalled clients do not occupy one of the handshake
+    /// slots forever.
+    pub fn new(acceptor: TlsAcceptor, incoming: I) -> TlsListener<I, S> {
+        TlsListener {
+            incoming: Some(incoming),
+            acceptor,
+            handshakes: FuturesUnordered::new(),
+            max_handshakes: DEFAULT_MAX_HANDSHAKES,
+        }
+    }
+
+    /// Sets the maximum number of handshakes performed concurrently.
+    ///
+    /// Defaults to 64.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn set_max_handshakes(&mut self, max: usize) {
+        assert!(max > 0, "`max_handshakes` must be at least 1");
+        self.max_handshakes = max;
+    }
+
+    /// Returns the maximum number of handshakes performed concurrently.
+    pub fn max_handshakes(&self) -> usize {
+        self.max_handshakes
+    }
+
+    /// Returns the number of handshakes currently in flight.
+    pub fn pending_handshakes(&self) -> usize {
+        self.handshakes.len()
+    }
+
+    /// Returns a reference to the acceptor used for new connections.
+    pub fn acceptor(&self) -> &TlsAcceptor {
+        &self.acceptor
+    }
+}
+
+impl<I, S, E> Stream for TlsListener<I, S>
+where
+    I: Stream<Item = Result<S, E>> + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+    Error: From<E>,
+{
+    type Item = Result<TlsStream<S>, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while this.handshakes.len() < this.max_handshakes {
+            let incoming = match this.incoming {
+                Some(ref mut incoming) => incoming,
+                None => break,
+            };
+            match Pin::new(incoming).poll_next(cx) {
+                Poll::Ready(Some(Ok(stream))) => {
+                    let handshake = this.acceptor.accept(stream);
+                    this.handshakes.push(handshake);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => this.incoming = None,
+                Poll::Pending => break,
+            }
+        }
+
+        if !this.handshakes.is_empty() {
+            if let Poll::Ready(Some(res)) = Pin::new(&mut this.handshakes).poll_next(cx) {
+                return Poll::Ready(Some(res));
+            }
+        }
+
+        if this.incoming.is_none() && this.handshakes.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<I, S> fmt::Debug for TlsListener<I, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsListener")
+            .field("pending_handshakes", &self.handshakes.len())
+            .field("max_handshakes", &self.max_handshakes)
+            .finish()
+    }
+}
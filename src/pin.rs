@@ -0,0 +1,102 @@
+use crate::Certificate;
+
+use sha2::{Digest, Sha256};
+
+use std::io;
+
+/// A set of pinned public keys, which the certificate chain presented by a
+/// server must contain one of, as set with `TlsConnector::set_pins`.
+///
+/// A pin is the SHA-256 digest of a certificate's DER encoded
+/// SubjectPublicKeyInfo, as used by HTTP Public Key Pinning and most pinning
+/// libraries. Pinning the public key, rather than the whole certificate,
+/// keeps the pin valid when a certificate is renewed with the same key.
+///
+/// Pins of backup keys, which are not in use yet, are added to the set like
+/// any other pin. A chain matching any pin in the set is accepted, so that
+/// connections keep working once the server switches over to a backup key.
+#[derive(Debug, Clone, Default)]
+pub struct PinSet {
+    pins: Vec<[u8; 32]>,
+    report_only: bool,
+}
+
+impl PinSet {
+    /// Creates an empty set of pins, which enforces them.
+    pub fn new() -> PinSet {
+        PinSet::default()
+    }
+
+    /// Adds a pin, given as the SHA-256 digest of a SubjectPublicKeyInfo.
+    pub fn insert(&mut self, sha256: [u8; 32]) {
+        if !self.pins.contains(&sha256) {
+            self.pins.push(sha256);
+        }
+    }
+
+    /// Adds a pin given in base64, optionally prefixed with `sha256/`, as
+    /// printed by e.g.
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+    ///
+    /// Returns an error of kind `InvalidInput` if `pin` is not the base64
+    /// encoding of a SHA-256 digest.
+    pub fn insert_base64(&mut self, pin: &str) -> io::Result<()> {
+        let pin = pin.strip_prefix("sha256/").unwrap_or(pin);
+        match base64::decode(pin) {
+            Ok(ref digest) if digest.len() == 32 => {
+                let mut sha256 = [0; 32];
+                sha256.copy_from_slice(digest);
+                self.insert(sha256);
+                Ok(())
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid SHA-256 pin `{}`", pin),
+            )),
+        }
+    }
+
+    /// Adds a pin for the public key of `cert`.
+    ///
+    /// Returns an error of kind `InvalidData` if the certificate cannot be
+    /// parsed.
+    pub fn insert_certificate(&mut self, cert: &Certificate) -> io::Result<()> {
+        self.insert(Sha256::digest(cert.public_key_info()?).into());
+        Ok(())
+    }
+
+    /// Returns the number of pins in the set.
+    pub fn len(&self) -> usize {
+        self.pins.len()
+    }
+
+    /// Returns `true` if the set contains no pins.
+    pub fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+
+    /// Sets whether chains which do not match any pin are only reported,
+    /// rather than rejected.
+    ///
+    /// Mismatches are logged as warnings through the `log` crate in either
+    /// mode. Report-only mode allows rolling out pins and checking that they
+    /// match before enforcing them. Defaults to `false`.
+    pub fn set_report_only(&mut self, report_only: bool) {
+        self.report_only = report_only;
+    }
+
+    /// Returns whether mismatches are only reported, as configured with
+    /// `set_report_only`.
+    pub fn report_only(&self) -> bool {
+        self.report_only
+    }
+
+    /// Returns whether the public key of any certificate in `chain` is
+    /// pinned. Certificates which cannot be parsed never match.
+    pub(crate) fn matches(&self, chain: &[Certificate]) -> bool {
+        chain.iter().any(|cert| match cert.public_key_info() {
+            Ok(spki) => self.pins.contains(&Sha256::digest(spki).into()),
+            Err(_) => false,
+        })
+    }
+}
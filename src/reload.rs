@@ -0,0 +1,220 @@
+use crate::{Error, TlsAcceptor};
+
+use tokio::stream::Stream;
+use tokio::task::JoinHandle;
+use tokio::time::Interval;
+
+use std::fmt;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+
+/// A handle for replacing the acceptor behind a `TlsAcceptor` created with
+/// `TlsAcceptor::reloadable`.
+///
+/// Replacing the acceptor only affects connections accepted afterwards.
+/// Handshakes which are already in progress, and established `TlsStream`s,
+/// keep using the acceptor they were started with.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    current: Arc<RwLock<TlsAcceptor>>,
+}
+
+/// The files a server identity is loaded from by `ReloadHandle::watch_identity`.
+#[derive(Debug, Clone)]
+pub enum IdentityFiles {
+    /// A PEM encoded certificate chain, leaf first, and a PEM encoded PKCS#8
+    /// private key.
+    Pem {
+        /// The path of the certificate chain.
+        cert: PathBuf,
+        /// The path of the private key.
+        key: PathBuf,
+    },
+    /// A DER encoded PKCS#12 archive holding the certificate chain and the
+    /// private key.
+    Pkcs12 {
+        /// The path of the archive.
+        path: PathBuf,
+        /// The password the archive is protected with.
+        password: String,
+    },
+}
+
+/// A stream which reloads an acceptor whenever the files it is loaded from
+/// change, created by `ReloadHandle::watch` or `ReloadHandle::watch_identity`.
+///
+/// The files are checked each time the interval passed to `watch` elapses,
+/// and the acceptor is reloaded if the modification time or the size of any
+/// of them changed since the previous check. The outcome of each reload is
+/// yielded by the stream. When a reload fails, for instance because a file
+/// was only partly written, the previous acceptor stays in use and the reload
+/// is attempted again on the next change.
+///
+/// Reloads read and parse the files on tokio's blocking thread pool, so the
+/// stream must be polled from within a runtime. Only the modification times
+/// and sizes are checked when the stream is polled.
+///
+/// The stream must be polled for reloads to happen, typically by spawning a
+/// task which logs the yielded errors. It ends once the reloadable
+/// `TlsAcceptor`, its clones and all `ReloadHandle`s for it have been dropped.
+pub struct Watch {
+    current: Weak<RwLock<TlsAcceptor>>,
+    paths: Vec<PathBuf>,
+    state: Option<Vec<Option<(SystemTime, u64)>>>,
+    interval: Interval,
+    load: Arc<Mutex<Load>>,
+    loading: Option<JoinHandle<Result<TlsAcceptor, Error>>>,
+}
+
+type Load = Box<dyn FnMut() -> Result<TlsAcceptor, Error> + Send>;
+
+pub(crate) fn new(acceptor: TlsAcceptor) -> ReloadHandle {
+    ReloadHandle {
+        current: Arc::new(RwLock::new(acceptor)),
+    }
+}
+
+impl ReloadHandle {
+    pub(crate) fn shared(&self) -> Arc<RwLock<TlsAcceptor>> {
+        self.current.clone()
+    }
+
+    /// Replaces the acceptor used for new connections.
+    pub fn reload(&self, acceptor: TlsAcceptor) {
+        *self.current.write().unwrap() = acceptor;
+    }
+
+    /// Returns the acceptor currently used for new connections.
+    pub fn current(&self) -> TlsAcceptor {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Returns a stream which reloads the acceptor with `load` whenever one
+    /// of `paths` changes.
+    ///
+    /// `paths` are checked every `interval`. This allows reloading acceptors
+    /// of any backend from any kind of files.
+    pub fn watch<F>(&self, paths: Vec<PathBuf>, interval: Duration, load: F) -> Watch
+    where
+        F: FnMut() -> Result<TlsAcceptor, Error> + Send + 'static,
+    {
+        Watch {
+            current: Arc::downgrade(&self.current),
+            paths,
+            state: None,
+            interval: tokio::time::interval(interval),
+            load: Arc::new(Mutex::new(Box::new(load))),
+            loading: None,
+        }
+    }
+
+    /// Returns a stream which reloads the acceptor with a `native-tls`
+    /// acceptor for the identity in `files` whenever they change.
+    ///
+    /// `files` are checked every `interval`.
+    pub fn watch_identity(&self, files: IdentityFiles, interval: Duration) -> Watch {
+        let paths = match files {
+            IdentityFiles::Pem { ref cert, ref key } => vec![cert.clone(), key.clone()],
+            IdentityFiles::Pkcs12 { ref path, .. } => vec![path.clone()],
+        };
+        self.watch(paths, interval, move || files.load())
+    }
+}
+
+impl fmt::Debug for ReloadHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadHandle").finish()
+    }
+}
+
+impl IdentityFiles {
+    /// Loads the identity from the files and creates a `native-tls` acceptor
+    /// for it.
+    pub fn load(&self) -> Result<TlsAcceptor, Error> {
+        let identity = match *self {
+            IdentityFiles::Pem { ref cert, ref key } => {
+                native_tls::Identity::from_pkcs8(&fs::read(cert)?, &fs::read(key)?)?
+            }
+            IdentityFiles::Pkcs12 {
+                ref path,
+                ref password,
+            } => native_tls::Identity::from_pkcs12(&fs::read(path)?, password)?,
+        };
+        Ok(native_tls::TlsAcceptor::new(identity)?.into())
+    }
+}
+
+impl Watch {
+    fn snapshot(&self) -> Vec<Option<(SystemTime, u64)>> {
+        self.paths
+            .iter()
+            .map(|path| {
+                let meta = fs::metadata(path).ok()?;
+                Some((meta.modified().ok()?, meta.len()))
+            })
+            .collect()
+    }
+}
+
+impl Stream for Watch {
+    type Item = Result<(), Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(ref mut loading) = this.loading {
+                let res = match Pin::new(loading).poll(cx) {
+                    Poll::Ready(res) => res,
+                    Poll::Pending => return Poll::Pending,
+                };
+                this.loading = None;
+                let res = res.unwrap_or_else(|e| {
+                    Err(io::Error::new(io::ErrorKind::Other, e.to_string()).into())
+                });
+
+                let current = match this.current.upgrade() {
+                    Some(current) => current,
+                    None => return Poll::Ready(None),
+                };
+                let res = res.map(|acceptor| {
+                    *current.write().unwrap() = acceptor;
+                });
+                return Poll::Ready(Some(res));
+            }
+
+            if this.current.strong_count() == 0 {
+                return Poll::Ready(None);
+            }
+            if this.interval.poll_tick(cx).is_pending() {
+                return Poll::Pending;
+            }
+
+            let snapshot = this.snapshot();
+            let changed = match this.state {
+                Some(ref state) => *state != snapshot,
+                None => false,
+            };
+            this.state = Some(snapshot);
+            if changed {
+                let load = this.load.clone();
+                this.loading = Some(tokio::task::spawn_blocking(move || {
+                    let mut load = load.lock().unwrap_or_else(PoisonError::into_inner);
+                    load()
+                }));
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Watch").field("paths", &self.paths).finish()
+    }
+}
@@ -0,0 +1,124 @@
+use sha2::{Digest, Sha256};
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// How an acceptor lets clients resume the sessions of earlier connections,
+/// set with `TlsAcceptor::set_session_resumption`.
+///
+/// A resumed session skips the certificate exchange and key agreement of a
+/// full handshake. Whether an accepted connection resumed a session is
+/// reported by `TlsStream::session_resumed`.
+#[derive(Debug, Clone)]
+pub enum SessionResumption {
+    /// Sessions are never resumed, so every handshake is a full one.
+    Disabled,
+    /// Sessions are kept in a cache holding up to the given number of them,
+    /// which clients refer to by ID. The cache is shared by the clones of the
+    /// acceptor, but not across servers. A size of 0 disables resumption.
+    Cache(usize),
+    /// Sessions are sent to clients in tickets encrypted with the given keys,
+    /// so that the server keeps no state. Servers using the same keys resume
+    /// each other's sessions.
+    Tickets(TicketKeys),
+}
+
+/// The keys encrypting the session tickets of `SessionResumption::Tickets`.
+///
+/// Tickets are encrypted with the current key, and decrypted with either the
+/// current or the previous one, so tickets issued before a key is rotated
+/// stay valid until the next rotation. Rotating keys at intervals at least as
+/// long as the ticket lifetime keeps tickets valid for their whole lifetime.
+///
+/// To resume sessions across a fleet of servers, give all of them the same
+/// keys and rotate them everywhere at about the same time. Tickets issued by
+/// servers which already rotated are not accepted by the others until they
+/// rotate too, and the clients presenting them fall back to a full handshake.
+#[derive(Clone)]
+pub struct TicketKeys {
+    keys: Arc<RwLock<Keys>>,
+    lifetime: Duration,
+}
+
+struct Keys {
+    current: TicketKey,
+    previous: Option<TicketKey>,
+}
+
+#[derive(Clone, Copy)]
+#[cfg_attr(not(feature = "rustls"), allow(dead_code))]
+pub(crate) struct TicketKey {
+    /// Identifies the key in the tickets it encrypts.
+    pub(crate) name: [u8; 16],
+    pub(crate) key: [u8; 32],
+}
+
+impl TicketKey {
+    fn new(key: [u8; 32]) -> TicketKey {
+        let mut name = [0; 16];
+        name.copy_from_slice(&Sha256::digest(key)[..16]);
+        TicketKey { name, key }
+    }
+}
+
+impl TicketKeys {
+    /// Creates ticket keys encrypting tickets with `key`, which should be
+    /// random, e.g. read from a secret shared by the servers of a fleet.
+    pub fn new(key: [u8; 32]) -> TicketKeys {
+        TicketKeys {
+            keys: Arc::new(RwLock::new(Keys {
+                current: TicketKey::new(key),
+                previous: None,
+            })),
+            lifetime: Duration::from_secs(12 * 60 * 60),
+        }
+    }
+
+    /// Makes `key` the current key, keeping the current one as the previous
+    /// key to decrypt the tickets it was used for.
+    ///
+    /// The keys are shared by the clones of these keys, so the rotation
+    /// applies to all the acceptors they were set on.
+    pub fn rotate(&self, key: [u8; 32]) {
+        let mut keys = self.keys.write().unwrap();
+        keys.previous = Some(keys.current);
+        keys.current = TicketKey::new(key);
+    }
+
+    /// Sets how long clients are told that tickets are valid for.
+    ///
+    /// TLS 1.3 limits this to 7 days. Defaults to 12 hours.
+    pub fn set_lifetime(&mut self, lifetime: Duration) {
+        self.lifetime = lifetime;
+    }
+
+    /// Returns the lifetime of tickets, as configured with `set_lifetime`.
+    pub fn lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    /// Returns the key tickets are encrypted with.
+    #[cfg(feature = "rustls")]
+    pub(crate) fn current(&self) -> TicketKey {
+        self.keys.read().unwrap().current
+    }
+
+    /// Returns the current or previous key named `name`, if any.
+    #[cfg(feature = "rustls")]
+    pub(crate) fn get(&self, name: &[u8]) -> Option<TicketKey> {
+        let keys = self.keys.read().unwrap();
+        Some(keys.current)
+            .into_iter()
+            .chain(keys.previous)
+            .find(|key| key.name[..] == *name)
+    }
+}
+
+impl fmt::Debug for TicketKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TicketKeys")
+            .field("lifetime", &self.lifetime)
+            .finish()
+    }
+}
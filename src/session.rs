@@ -0,0 +1,146 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Counters of the session cache of a `TlsConnector`, as returned by
+/// `TlsConnector::session_cache_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SessionCacheStats {
+    hits: u64,
+    misses: u64,
+    resumed: u64,
+}
+
+impl SessionCacheStats {
+    /// Returns the number of handshakes for which a cached session was found,
+    /// and offered to the server.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Returns the number of handshakes for which no cached session was
+    /// found.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Returns the number of handshakes in which the server accepted the
+    /// offered session.
+    ///
+    /// Only backends for which `TlsStream::session_resumed` reports
+    /// resumption count these.
+    pub fn resumed(&self) -> u64 {
+        self.resumed
+    }
+
+    /// Returns the fraction of handshakes for which a cached session was
+    /// found, or 0 if no handshake has been made.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+/// A cache of the sessions established by a connector, as serialized by its
+/// backend, which evicts the oldest sessions once it is full.
+///
+/// `native-tls` does not support session caching, so without the other
+/// backends a cache is never created.
+#[cfg_attr(not(any(feature = "openssl", feature = "rustls")), allow(dead_code))]
+pub(crate) struct SessionCache {
+    capacity: usize,
+    sessions: Mutex<Sessions>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    resumed: AtomicU64,
+}
+
+#[derive(Default)]
+#[cfg_attr(not(any(feature = "openssl", feature = "rustls")), allow(dead_code))]
+struct Sessions {
+    map: HashMap<Vec<u8>, Vec<u8>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+#[cfg_attr(not(any(feature = "openssl", feature = "rustls")), allow(dead_code))]
+impl SessionCache {
+    pub(crate) fn new(capacity: usize) -> SessionCache {
+        SessionCache {
+            capacity,
+            sessions: Mutex::new(Sessions::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            resumed: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the session stored under `key` for a handshake, counting a
+    /// hit or a miss.
+    pub(crate) fn lookup(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let session = self.get(key);
+        let counter = match session {
+            Some(_) => &self.hits,
+            None => &self.misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        session
+    }
+
+    /// Returns the value stored under `key`, without counting it as a
+    /// lookup.
+    pub(crate) fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.sessions.lock().unwrap().map.get(key).cloned()
+    }
+
+    pub(crate) fn insert(&self, key: Vec<u8>, session: Vec<u8>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.map.insert(key.clone(), session).is_none() {
+            sessions.order.push_back(key);
+        }
+        while sessions.order.len() > self.capacity {
+            let oldest = sessions.order.pop_front().unwrap();
+            sessions.map.remove(&oldest);
+        }
+    }
+
+    pub(crate) fn record_resumed(&self) {
+        self.resumed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> SessionCacheStats {
+        SessionCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            resumed: self.resumed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Returns the key under which the sessions established with `domain`, and
+/// the server at `addr` if known, are cached.
+pub(crate) fn key(domain: &str, addr: Option<SocketAddr>) -> Vec<u8> {
+    match addr {
+        Some(addr) => format!("{}|{}", domain, addr).into_bytes(),
+        None => domain.as_bytes().to_vec(),
+    }
+}
+
+/// The cache of a client connection, for backends which store the sessions
+/// sent by the server once the handshake has completed, which only OpenSSL
+/// does.
+#[cfg_attr(not(feature = "openssl"), allow(dead_code))]
+pub(crate) struct ClientSessions {
+    pub(crate) cache: Arc<SessionCache>,
+    pub(crate) key: Vec<u8>,
+    /// The ID of the last session cached for the connection, if any.
+    pub(crate) seen: Vec<u8>,
+}
@@ -0,0 +1,222 @@
+use crate::client_hello::{self, ClientHello};
+use crate::{backend, Error, TlsAcceptor};
+
+use tokio::io::AsyncRead;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// The most bytes buffered while waiting for a complete ClientHello.
+const MAX_CLIENT_HELLO_LEN: usize = 64 * 1024;
+
+/// Chooses the acceptor which handles a connection, based on the server name
+/// the client requested through SNI.
+///
+/// A resolver is installed with `TlsAcceptor::with_resolver`. It is also
+/// implemented for closures taking the requested name.
+pub trait ServerNameResolver: Send + Sync {
+    /// Returns the acceptor to complete the handshake with, given the host
+    /// name sent by the client, or `None` to reject the connection.
+    ///
+    /// `server_name` is `None` if the client did not send the server_name
+    /// extension, as is the case when it connects to an IP address.
+    fn resolve(&self, server_name: Option<&str>) -> Option<TlsAcceptor>;
+
+    /// Returns the acceptor to complete the handshake with, given the whole
+    /// ClientHello sent by the client, or `None` to reject the connection.
+    ///
+    /// This allows routing connections on other parameters than the server
+    /// name, such as the protocols offered through ALPN. The default
+    /// implementation calls `resolve` with the requested server name.
+    fn resolve_client_hello(&self, hello: &ClientHello) -> Option<TlsAcceptor> {
+        self.resolve(hello.server_name())
+    }
+}
+
+impl<F> ServerNameResolver for F
+where
+    F: Fn(Option<&str>) -> Option<TlsAcceptor> + Send + Sync,
+{
+    fn resolve(&self, server_name: Option<&str>) -> Option<TlsAcceptor> {
+        self(server_name)
+    }
+}
+
+/// A `ServerNameResolver` which looks up the requested name in a table of
+/// acceptors.
+///
+/// Names are matched case-insensitively. An entry for `*.example.com` matches
+/// any name directly below `example.com`, but not `example.com` itself.
+/// Connections which match no entry, or which did not request a name, are
+/// handled by the default acceptor if one is set, and rejected otherwise.
+#[derive(Clone, Default)]
+pub struct ServerNameMap {
+    names: HashMap<String, TlsAcceptor>,
+    default: Option<TlsAcceptor>,
+}
+
+impl ServerNameMap {
+    /// Creates an empty map without a default acceptor.
+    pub fn new() -> ServerNameMap {
+        ServerNameMap::default()
+    }
+
+    /// Uses `acceptor` for connections requesting `name`, returning the
+    /// acceptor previously registered for it, if any.
+    pub fn insert(&mut self, name: &str, acceptor: TlsAcceptor) -> Option<TlsAcceptor> {
+        self.names.insert(normalize(name), acceptor)
+    }
+
+    /// Removes the acceptor registered for `name`, returning it.
+    pub fn remove(&mut self, name: &str) -> Option<TlsAcceptor> {
+        self.names.remove(&normalize(name))
+    }
+
+    /// Sets the acceptor for connections which match no other entry.
+    pub fn set_default(&mut self, acceptor: Option<TlsAcceptor>) {
+        self.default = acceptor;
+    }
+
+    /// Returns the acceptor which handles connections requesting
+    /// `server_name`.
+    pub fn get(&self, server_name: Option<&str>) -> Option<&TlsAcceptor> {
+        server_name
+            .map(normalize)
+            .and_then(|name| {
+                self.names.get(&name).or_else(|| {
+                    let (_, parent) = name.split_once('.')?;
+                    self.names.get(&format!("*.{}", parent))
+                })
+            })
+            .or(self.default.as_ref())
+    }
+}
+
+impl ServerNameResolver for ServerNameMap {
+    fn resolve(&self, server_name: Option<&str>) -> Option<TlsAcceptor> {
+        self.get(server_name).cloned()
+    }
+}
+
+impl fmt::Debug for ServerNameMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerNameMap")
+            .field("names", &self.names.keys())
+            .field("default", &self.default.is_some())
+            .finish()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Resolves the backend acceptor for a connection, following resolvers which
+/// return acceptors that are themselves resolver based.
+///
+/// `hello` is `None` if the client did not send a well-formed ClientHello.
+pub(crate) fn resolve(
+    acceptor: &TlsAcceptor,
+    hello: Option<&ClientHello>,
+) -> Result<backend::Acceptor, Error> {
+    let resolve = |resolver: &Arc<dyn ServerNameResolver>| match hello {
+        Some(hello) => resolver.resolve_client_hello(hello),
+        None => resolver.resolve(None),
+    };
+    let server_name = hello.and_then(|h| h.server_name());
+    let mut acceptor = Some(acceptor.clone());
+    loop {
+        match acceptor {
+            Some(TlsAcceptor {
+                inner: crate::AcceptorInner::Backend(inner),
+                ..
+            }) => return Ok(inner),
+            Some(TlsAcceptor {
+                inner: crate::AcceptorInner::Resolver(resolver),
+                ..
+            }) => acceptor = resolve(&resolver),
+            Some(TlsAcceptor {
+                inner: crate::AcceptorInner::Reloadable(current),
+                ..
+            }) => acceptor = Some(current.read().unwrap().clone()),
+            None => {
+                let msg = match server_name {
+                    Some(name) => format!("no TLS acceptor for server name `{}`", name),
+                    None => "no TLS acceptor for connections without a server name".to_owned(),
+                };
+                return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+            }
+        }
+    }
+}
+
+/// Reads from a transport until a complete ClientHello has been received.
+///
+/// Resolves to the transport, the bytes read from it, and the parsed message.
+/// If the client sent something other than a well-formed ClientHello, the
+/// message is `None` and the TLS backend gets to report the error.
+pub(crate) struct ReadClientHello<S> {
+    stream: Option<S>,
+    buf: Vec<u8>,
+}
+
+impl<S> ReadClientHello<S> {
+    /// Creates a future reading from `stream`, given the bytes already read
+    /// from it.
+    pub(crate) fn new(stream: S, prefix: Vec<u8>) -> ReadClientHello<S> {
+        ReadClientHello {
+            stream: Some(stream),
+            buf: prefix,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> Future for ReadClientHello<S> {
+    type Output = Result<(S, Vec<u8>, Option<ClientHello>), Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let stream = this
+            .stream
+            .as_mut()
+            .expect("future polled after completion");
+
+        let hello = loop {
+            match client_hello::parse(&this.buf) {
+                client_hello::Parse::Done(hello) => break Some(hello),
+                client_hello::Parse::Invalid => break None,
+                client_hello::Parse::Partial if this.buf.len() >= MAX_CLIENT_HELLO_LEN => {
+                    break None
+                }
+                client_hello::Parse::Partial => {}
+            }
+
+            let len = this.buf.len();
+            this.buf.resize(len + 4096, 0);
+            let res = Pin::new(&mut *stream).poll_read(cx, &mut this.buf[len..]);
+            let n = match res {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(e)) => {
+                    this.buf.truncate(len);
+                    return Poll::Ready(Err(e.into()));
+                }
+                Poll::Pending => {
+                    this.buf.truncate(len);
+                    return Poll::Pending;
+                }
+            };
+            this.buf.truncate(len + n);
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()));
+            }
+        };
+
+        let stream = this.stream.take().unwrap();
+        Poll::Ready(Ok((stream, std::mem::take(&mut this.buf), hello)))
+    }
+}
@@ -0,0 +1,267 @@
+use crate::{Accept, Error, TlsAcceptor, TlsStream};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Delay;
+
+use std::cmp;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const MAJOR_VERSION_TLS: u8 = 3;
+
+/// A connection accepted by `TlsAcceptor::accept_or_plaintext`, which is
+/// either a TLS session or a plaintext stream.
+// Boxing the TLS stream would only save moving it once, at the cost of
+// changing the public variant.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum MaybeTlsStream<S> {
+    /// The client started a TLS handshake, which has completed.
+    Tls(TlsStream<S>),
+    /// The client sent something other than a TLS handshake.
+    Plaintext(PlaintextStream<S>),
+}
+
+/// A plaintext stream returned by `TlsAcceptor::accept_or_plaintext`.
+///
+/// The bytes which were read from the stream to tell it apart from a TLS
+/// connection are returned by the first reads, followed by the rest of the
+/// stream, so none of the client's data is lost.
+pub struct PlaintextStream<S> {
+    inner: S,
+    prefix: Vec<u8>,
+    pos: usize,
+}
+
+/// Future returned from `TlsAcceptor::accept_or_plaintext` which will resolve
+/// once the kind of connection is known and, for TLS connections, the
+/// handshake has completed.
+pub struct AcceptOrPlaintext<S> {
+    acceptor: TlsAcceptor,
+    stream: Option<S>,
+    buf: Vec<u8>,
+    delay: Option<Delay>,
+    accept: Option<Accept<S>>,
+}
+
+impl<S> PlaintextStream<S> {
+    /// Returns a reference to the underlying stream.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying stream.
+    ///
+    /// Reading from it directly skips the bytes which have already been read
+    /// from it but not returned by this stream yet.
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Returns the underlying stream along with the bytes which have been
+    /// read from it but not returned by this stream yet.
+    pub fn into_inner(self) -> (S, Vec<u8>) {
+        let rest = self.prefix[self.pos..].to_vec();
+        (self.inner, rest)
+    }
+}
+
+impl<S> AcceptOrPlaintext<S> {
+    pub(crate) fn new(acceptor: TlsAcceptor, stream: S) -> AcceptOrPlaintext<S> {
+        AcceptOrPlaintext {
+            acceptor,
+            stream: Some(stream),
+            buf: Vec::new(),
+            delay: None,
+            accept: None,
+        }
+    }
+
+    fn plaintext(&mut self) -> MaybeTlsStream<S> {
+        MaybeTlsStream::Plaintext(PlaintextStream {
+            inner: self.stream.take().expect("future polled after completion"),
+            prefix: std::mem::take(&mut self.buf),
+            pos: 0,
+        })
+    }
+
+    fn poll_timeout(&mut self, cx: &mut Context<'_>) -> Poll<Result<MaybeTlsStream<S>, Error>> {
+        if let Some(timeout) = self.acceptor.handshake_timeout() {
+            let delay = self
+                .delay
+                .get_or_insert_with(|| tokio::time::delay_for(timeout));
+            if Pin::new(delay).poll(cx).is_ready() {
+                self.stream = None;
+                return Poll::Ready(Err(Error::timeout()));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+/// Tells from the first bytes sent by a client whether it is starting a TLS
+/// handshake, or `None` if more bytes are needed to tell.
+///
+/// A TLS connection starts with a handshake record, whose header holds the
+/// content type followed by the protocol version, which has a major version
+/// of 3 for every version of SSL 3.0 and TLS. No printable text starts with
+/// the content type byte.
+fn is_tls(buf: &[u8]) -> Option<bool> {
+    match *buf {
+        [] => None,
+        [typ, ..] if typ != CONTENT_TYPE_HANDSHAKE => Some(false),
+        [_] => None,
+        [_, major, ..] => Some(major == MAJOR_VERSION_TLS),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Future for AcceptOrPlaintext<S> {
+    type Output = Result<MaybeTlsStream<S>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        loop {
+            if let Some(ref mut accept) = this.accept {
+                return Pin::new(accept)
+                    .poll(cx)
+                    .map(|res| res.map(MaybeTlsStream::Tls));
+            }
+
+            match is_tls(&this.buf) {
+                Some(true) => {
+                    let stream = this.stream.take().expect("future polled after completion");
+                    let prefix = std::mem::take(&mut this.buf);
+                    // The handshake only gets the time left after sniffing.
+                    let accept = this.acceptor.accept_with_prefix(stream, prefix);
+                    this.accept = Some(accept.with_delay(this.delay.take()));
+                    continue;
+                }
+                Some(false) => return Poll::Ready(Ok(this.plaintext())),
+                None => {}
+            }
+
+            // Read no more than needed to tell, so that the rest of the
+            // stream is left to the TLS session or the plaintext protocol.
+            let stream = this
+                .stream
+                .as_mut()
+                .expect("future polled after completion");
+            let mut byte = [0];
+            match Pin::new(stream).poll_read(cx, &mut byte) {
+                Poll::Ready(Ok(0)) if this.buf.is_empty() => {
+                    this.stream = None;
+                    return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()));
+                }
+                // Whatever the client sent before closing the connection is
+                // not a TLS handshake.
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(this.plaintext())),
+                Poll::Ready(Ok(_)) => this.buf.push(byte[0]),
+                Poll::Ready(Err(e)) => {
+                    this.stream = None;
+                    return Poll::Ready(Err(e.into()));
+                }
+                Poll::Pending => return this.poll_timeout(cx),
+            }
+        }
+    }
+}
+
+impl<S> fmt::Debug for AcceptOrPlaintext<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AcceptOrPlaintext").finish()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for PlaintextStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.pos < this.prefix.len() {
+            let n = cmp::min(buf.len(), this.prefix.len() - this.pos);
+            buf[..n].copy_from_slice(&this.prefix[this.pos..this.pos + n]);
+            this.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for PlaintextStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for PlaintextStream<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PlaintextStream")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<S> AsyncRead for MaybeTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match *self.get_mut() {
+            MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_read(cx, buf),
+            MaybeTlsStream::Plaintext(ref mut s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<S> AsyncWrite for MaybeTlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match *self.get_mut() {
+            MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_write(cx, buf),
+            MaybeTlsStream::Plaintext(ref mut s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_flush(cx),
+            MaybeTlsStream::Plaintext(ref mut s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match *self.get_mut() {
+            MaybeTlsStream::Tls(ref mut s) => Pin::new(s).poll_shutdown(cx),
+            MaybeTlsStream::Plaintext(ref mut s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
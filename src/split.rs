@@ -0,0 +1,187 @@
+use crate::TlsStream;
+
+use bytes::Buf;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::fmt;
+use std::io;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+/// The readable half of a `TlsStream`, created by `TlsStream::into_split`.
+pub struct ReadHalf<S> {
+    shared: Arc<Shared<S>>,
+}
+
+/// The writable half of a `TlsStream`, created by `TlsStream::into_split`.
+pub struct WriteHalf<S> {
+    shared: Arc<Shared<S>>,
+}
+
+struct Shared<S> {
+    stream: Mutex<TlsStream<S>>,
+    wakers: Arc<Wakers>,
+    waker: Waker,
+}
+
+// Both halves drive the same TLS session, and reading may need to write to
+// `S` (and the other way around), e.g. to answer a renegotiation. As `S` only
+// remembers the last waker it was polled with for each direction, the session
+// is always polled with a waker which wakes up both halves, so that neither
+// half misses a wakeup meant for it.
+#[derive(Default)]
+struct Wakers {
+    read: Mutex<Option<Waker>>,
+    write: Mutex<Option<Waker>>,
+}
+
+pub(crate) fn split<S>(stream: TlsStream<S>) -> (ReadHalf<S>, WriteHalf<S>) {
+    let wakers = Arc::new(Wakers::default());
+    let shared = Arc::new(Shared {
+        stream: Mutex::new(stream),
+        waker: Waker::from(wakers.clone()),
+        wakers,
+    });
+    let read = ReadHalf {
+        shared: shared.clone(),
+    };
+    let write = WriteHalf { shared };
+    (read, write)
+}
+
+impl<S> Shared<S> {
+    fn poll<F, R>(&self, cx: &mut Context<'_>, slot: &Mutex<Option<Waker>>, f: F) -> Poll<R>
+    where
+        F: FnOnce(Pin<&mut TlsStream<S>>, &mut Context<'_>) -> Poll<R>,
+        S: Unpin,
+    {
+        {
+            let mut slot = slot.lock().unwrap();
+            match *slot {
+                Some(ref w) if w.will_wake(cx.waker()) => {}
+                _ => *slot = Some(cx.waker().clone()),
+            }
+        }
+
+        let mut cx = Context::from_waker(&self.waker);
+        let mut stream = self.stream.lock().unwrap();
+        f(Pin::new(&mut *stream), &mut cx)
+    }
+}
+
+impl Wake for Wakers {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if let Some(w) = self.read.lock().unwrap().take() {
+            w.wake();
+        }
+        if let Some(w) = self.write.lock().unwrap().take() {
+            w.wake();
+        }
+    }
+}
+
+impl<S> ReadHalf<S> {
+    /// Returns `true` if this `ReadHalf` and the given `WriteHalf` were split
+    /// from the same `TlsStream`.
+    pub fn is_pair_of(&self, other: &WriteHalf<S>) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    /// Reunites this `ReadHalf` with its `WriteHalf`, returning the original
+    /// `TlsStream`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the two halves were not split from the same `TlsStream`.
+    pub fn unsplit(self, other: WriteHalf<S>) -> TlsStream<S> {
+        assert!(
+            self.is_pair_of(&other),
+            "unrelated `ReadHalf` and `WriteHalf` passed to `unsplit`"
+        );
+        drop(other);
+        let shared = Arc::try_unwrap(self.shared)
+            .ok()
+            .expect("`TlsStream` halves shared more than twice");
+        shared.stream.into_inner().unwrap()
+    }
+}
+
+impl<S> WriteHalf<S> {
+    /// Returns `true` if this `WriteHalf` and the given `ReadHalf` were split
+    /// from the same `TlsStream`.
+    pub fn is_pair_of(&self, other: &ReadHalf<S>) -> bool {
+        other.is_pair_of(self)
+    }
+}
+
+impl<S> AsyncRead for ReadHalf<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    unsafe fn prepare_uninitialized_buffer(&self, _: &mut [MaybeUninit<u8>]) -> bool {
+        false
+    }
+
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let shared = &self.shared;
+        shared.poll(cx, &shared.wakers.read, |s, cx| s.poll_read(cx, buf))
+    }
+}
+
+impl<S> AsyncWrite for WriteHalf<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let shared = &self.shared;
+        shared.poll(cx, &shared.wakers.write, |s, cx| s.poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let shared = &self.shared;
+        shared.poll(cx, &shared.wakers.write, |s, cx| s.poll_flush(cx))
+    }
+
+    fn poll_write_buf<B: Buf>(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut B,
+    ) -> Poll<io::Result<usize>>
+    where
+        Self: Sized,
+    {
+        let shared = &self.shared;
+        shared.poll(cx, &shared.wakers.write, |s, cx| s.poll_write_buf(cx, buf))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let shared = &self.shared;
+        shared.poll(cx, &shared.wakers.write, |s, cx| s.poll_shutdown(cx))
+    }
+}
+
+impl<S> fmt::Debug for ReadHalf<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadHalf").finish()
+    }
+}
+
+impl<S> fmt::Debug for WriteHalf<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteHalf").finish()
+    }
+}
@@ -0,0 +1,285 @@
+//! Upgrades of plaintext connections to TLS for protocols using STARTTLS.
+//!
+//! Protocols such as SMTP and IMAP start out in plaintext and switch to TLS
+//! once both sides have agreed to, so the handshake starts in the middle of
+//! the connection. The functions in this module perform the plaintext part of
+//! the exchange for either side and then the TLS handshake, passing on any
+//! bytes read past the end of the plaintext part through
+//! `TlsConnector::connect_with_prefix` and `TlsAcceptor::accept_with_prefix`.
+//!
+//! Only the commands needed to get to the handshake are supported. The
+//! server functions reject every other command with the protocol's
+//! respective error response, until the client issues STARTTLS.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use tokio::net::TcpStream;
+//! use tokio_tls::{starttls, TlsConnector};
+//!
+//! # async fn run(connector: TlsConnector) -> Result<(), tokio_tls::Error> {
+//! let socket = TcpStream::connect("mail.example.com:587").await?;
+//! let domain = "mail.example.com";
+//! let stream = starttls::smtp_client(&connector, domain, "client.example.com", socket).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::{Error, TlsAcceptor, TlsConnector, TlsStream};
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use std::io;
+
+/// The longest line accepted from the peer, including the line ending.
+const MAX_LINE_LEN: u64 = 4096;
+
+/// Performs the client side of an SMTP STARTTLS exchange over `stream`,
+/// followed by a TLS handshake for `domain`.
+///
+/// The server's greeting is read, `EHLO` is sent with `client_name`, and the
+/// server must then advertise the STARTTLS extension and accept the
+/// `STARTTLS` command. As required by RFC 3207, the client should issue
+/// `EHLO` again over the returned stream before sending mail.
+pub async fn smtp_client<S>(
+    connector: &TlsConnector,
+    domain: &str,
+    client_name: &str,
+    stream: S,
+) -> Result<TlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+
+    smtp_reply(&mut stream, 220).await?;
+    send(&mut stream, &format!("EHLO {}\r\n", client_name)).await?;
+    let extensions = smtp_reply(&mut stream, 250).await?;
+    // The first line of the reply is the greeting, the others name the
+    // supported extensions.
+    if !extensions
+        .iter()
+        .skip(1)
+        .any(|ext| ext.eq_ignore_ascii_case("STARTTLS"))
+    {
+        return Err(protocol_error("SMTP server does not support STARTTLS"));
+    }
+    send(&mut stream, "STARTTLS\r\n").await?;
+    smtp_reply(&mut stream, 220).await?;
+
+    let prefix = stream.buffer().to_vec();
+    connector
+        .connect_with_prefix(domain, stream.into_inner(), prefix)
+        .await
+}
+
+/// Performs the server side of an SMTP STARTTLS exchange over `stream`,
+/// followed by a TLS handshake.
+///
+/// A greeting naming `server_name` is sent and commands are answered until
+/// the client issues `STARTTLS`. `EHLO` is answered with the STARTTLS
+/// extension, and `QUIT` ends the exchange with an error, as does the client
+/// closing the connection.
+pub async fn smtp_server<S>(
+    acceptor: &TlsAcceptor,
+    server_name: &str,
+    stream: S,
+) -> Result<TlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+
+    send(&mut stream, &format!("220 {} ESMTP ready\r\n", server_name)).await?;
+    loop {
+        let line = read_line(&mut stream).await?;
+        let command = line.split(' ').next().unwrap_or("").to_ascii_uppercase();
+        let reply = match &command[..] {
+            "EHLO" => format!("250-{}\r\n250 STARTTLS\r\n", server_name),
+            "HELO" => format!("250 {}\r\n", server_name),
+            "NOOP" => "250 OK\r\n".to_owned(),
+            "STARTTLS" => break,
+            "QUIT" => {
+                send(&mut stream, "221 Bye\r\n").await?;
+                return Err(protocol_error("SMTP client quit before STARTTLS"));
+            }
+            _ => "530 Must issue a STARTTLS command first\r\n".to_owned(),
+        };
+        send(&mut stream, &reply).await?;
+    }
+    send(&mut stream, "220 Ready to start TLS\r\n").await?;
+
+    let prefix = stream.buffer().to_vec();
+    acceptor
+        .accept_with_prefix(stream.into_inner(), prefix)
+        .await
+}
+
+/// Performs the client side of an IMAP STARTTLS exchange over `stream`,
+/// followed by a TLS handshake for `domain`.
+///
+/// The server's greeting is read and the `STARTTLS` command is sent, which
+/// the server must complete successfully. As required by RFC 3501, the
+/// client should ask for the server's capabilities again over the returned
+/// stream.
+pub async fn imap_client<S>(
+    connector: &TlsConnector,
+    domain: &str,
+    stream: S,
+) -> Result<TlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+
+    let greeting = read_line(&mut stream).await?;
+    if !greeting.starts_with("* OK") {
+        return Err(protocol_error(&format!(
+            "unexpected IMAP greeting `{}`",
+            greeting
+        )));
+    }
+    send(&mut stream, "a001 STARTTLS\r\n").await?;
+    loop {
+        let line = read_line(&mut stream).await?;
+        // Untagged responses may come before the command's completion.
+        if line.starts_with("* ") {
+            continue;
+        }
+        if line.starts_with("a001 OK") {
+            break;
+        }
+        return Err(protocol_error(&format!(
+            "IMAP server rejected STARTTLS: `{}`",
+            line
+        )));
+    }
+
+    let prefix = stream.buffer().to_vec();
+    connector
+        .connect_with_prefix(domain, stream.into_inner(), prefix)
+        .await
+}
+
+/// Performs the server side of an IMAP STARTTLS exchange over `stream`,
+/// followed by a TLS handshake.
+///
+/// A greeting naming `server_name` is sent and commands are answered until
+/// the client issues `STARTTLS`. `CAPABILITY` reports STARTTLS and disables
+/// logins, and `LOGOUT` ends the exchange with an error, as does the client
+/// closing the connection.
+pub async fn imap_server<S>(
+    acceptor: &TlsAcceptor,
+    server_name: &str,
+    stream: S,
+) -> Result<TlsStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    const CAPABILITIES: &str = "IMAP4rev1 STARTTLS LOGINDISABLED";
+
+    let mut stream = BufReader::new(stream);
+
+    let greeting = format!(
+        "* OK [CAPABILITY {}] {} ready\r\n",
+        CAPABILITIES, server_name
+    );
+    send(&mut stream, &greeting).await?;
+    loop {
+        let line = read_line(&mut stream).await?;
+        let mut words = line.split(' ');
+        let tag = words.next().unwrap_or("");
+        let command = words.next().unwrap_or("").to_ascii_uppercase();
+        if tag.is_empty() || tag == "*" {
+            send(&mut stream, "* BAD Missing command tag\r\n").await?;
+            continue;
+        }
+        let reply = match &command[..] {
+            "CAPABILITY" => format!(
+                "* CAPABILITY {}\r\n{} OK CAPABILITY completed\r\n",
+                CAPABILITIES, tag
+            ),
+            "NOOP" => format!("{} OK NOOP completed\r\n", tag),
+            "STARTTLS" => {
+                let reply = format!("{} OK Begin TLS negotiation now\r\n", tag);
+                send(&mut stream, &reply).await?;
+                break;
+            }
+            "LOGOUT" => {
+                let reply = format!("* BYE Logging out\r\n{} OK LOGOUT completed\r\n", tag);
+                send(&mut stream, &reply).await?;
+                return Err(protocol_error("IMAP client logged out before STARTTLS"));
+            }
+            _ => format!("{} BAD Must issue a STARTTLS command first\r\n", tag),
+        };
+        send(&mut stream, &reply).await?;
+    }
+
+    let prefix = stream.buffer().to_vec();
+    acceptor
+        .accept_with_prefix(stream.into_inner(), prefix)
+        .await
+}
+
+/// Reads an SMTP reply, which must have the status `code`, returning the
+/// text of its lines.
+async fn smtp_reply<S>(stream: &mut BufReader<S>, code: u16) -> Result<Vec<String>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut lines = Vec::new();
+    loop {
+        let line = read_line(stream).await?;
+        let status = line.get(..3).and_then(|s| s.parse::<u16>().ok());
+        if status != Some(code) {
+            return Err(protocol_error(&format!(
+                "unexpected SMTP reply `{}`, expected {}",
+                line, code
+            )));
+        }
+        lines.push(line.get(4..).unwrap_or("").to_owned());
+        // A dash after the code marks all lines but the last.
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(lines);
+        }
+    }
+}
+
+/// Reads a line terminated by CRLF, or LF, returning it without the line
+/// ending.
+async fn read_line<S>(stream: &mut BufReader<S>) -> Result<String, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = Vec::new();
+    (&mut *stream)
+        .take(MAX_LINE_LEN)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.last() != Some(&b'\n') {
+        return Err(if line.len() as u64 == MAX_LINE_LEN {
+            protocol_error("line sent by peer is too long")
+        } else {
+            io::Error::from(io::ErrorKind::UnexpectedEof).into()
+        });
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|_| protocol_error("line sent by peer is not valid UTF-8"))
+}
+
+async fn send<S>(stream: &mut BufReader<S>, data: &str) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = stream.get_mut();
+    stream.write_all(data.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn protocol_error(msg: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
@@ -0,0 +1,59 @@
+use crate::{Certificate, Error, PinSet};
+
+use std::sync::Arc;
+
+/// Verifies the certificate chains presented by servers, in place of the
+/// backend's own verification, as set with
+/// `TlsConnector::set_certificate_verifier`.
+///
+/// This is implemented for closures taking the same arguments as `verify`,
+/// so a verifier can be set as e.g.
+/// `connector.set_certificate_verifier(|chain: &[Certificate], name: &str| Ok(()))`.
+pub trait CertificateVerifier: Send + Sync {
+    /// Verifies `chain`, the certificates presented by the server leaf first,
+    /// for `server_name`, the domain passed to `TlsConnector::connect`.
+    ///
+    /// Returning an error rejects the connection, with the error's message
+    /// as the reason.
+    fn verify(&self, chain: &[Certificate], server_name: &str) -> Result<(), String>;
+}
+
+impl<F> CertificateVerifier for F
+where
+    F: Fn(&[Certificate], &str) -> Result<(), String> + Send + Sync,
+{
+    fn verify(&self, chain: &[Certificate], server_name: &str) -> Result<(), String> {
+        self(chain, server_name)
+    }
+}
+
+/// The checks of the certificate chain presented by a server which run once
+/// a client handshake has completed, in addition to the backend's.
+pub(crate) struct ServerChecks {
+    pub(crate) domain: String,
+    pub(crate) verifier: Option<Arc<dyn CertificateVerifier>>,
+    pub(crate) pins: Option<Arc<PinSet>>,
+}
+
+impl ServerChecks {
+    pub(crate) fn check(&self, chain: &[Certificate]) -> Result<(), Error> {
+        if let Some(ref verifier) = self.verifier {
+            verifier
+                .verify(chain, &self.domain)
+                .map_err(Error::rejected)?;
+        }
+        if let Some(ref pins) = self.pins {
+            if !pins.matches(chain) {
+                log::warn!(
+                    "certificate chain presented by {} does not match any pinned public key",
+                    self.domain
+                );
+                if !pins.report_only() {
+                    let reason = "no certificate matches a pinned public key".to_owned();
+                    return Err(Error::rejected(reason));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,11 +2,10 @@
 
 use cfg_if::cfg_if;
 use env_logger;
-use native_tls::TlsConnector;
-use std::io::{self, Error};
 use std::net::ToSocketAddrs;
 use tokio::net::TcpStream;
 use tokio_tls;
+use tokio_tls::{Error, ErrorKind};
 
 macro_rules! t {
     ($e:expr) => {
@@ -17,67 +16,55 @@ macro_rules! t {
     };
 }
 
-cfg_if! {
-    if #[cfg(feature = "force-rustls")] {
-        fn verify_failed(err: &Error, s:  &str) {
-            let err = err.to_string();
-            assert!(err.contains(s), "bad error: {}", err);
-        }
+#[cfg(feature = "rustls")]
+fn connector() -> tokio_tls::TlsConnector {
+    let mut config = tokio_tls::rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    std::sync::Arc::new(config).into()
+}
 
-        fn assert_expired_error(err: &Error) {
-            verify_failed(err, "CertExpired");
-        }
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+fn connector() -> tokio_tls::TlsConnector {
+    use tokio_tls::openssl::ssl::{SslConnector, SslMethod};
 
-        fn assert_wrong_host(err: &Error) {
-            verify_failed(err, "CertNotValidForName");
-        }
+    let builder = t!(SslConnector::builder(SslMethod::tls()));
+    builder.build().into()
+}
 
-        fn assert_self_signed(err: &Error) {
-            verify_failed(err, "UnknownIssuer");
-        }
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+fn connector() -> tokio_tls::TlsConnector {
+    let builder = native_tls::TlsConnector::builder();
+    t!(builder.build()).into()
+}
 
-        fn assert_untrusted_root(err: &Error) {
-            verify_failed(err, "UnknownIssuer");
-        }
-    } else if #[cfg(any(feature = "force-openssl",
+cfg_if! {
+    if #[cfg(feature = "rustls")] {
+        const EXPIRED: ErrorKind = ErrorKind::CertificateExpired;
+        const WRONG_HOST: ErrorKind = ErrorKind::HostnameMismatch;
+        // webpki does not tell self-signed certificates apart.
+        const SELF_SIGNED: ErrorKind = ErrorKind::UntrustedRoot;
+        const UNTRUSTED_ROOT: ErrorKind = ErrorKind::UntrustedRoot;
+    } else if #[cfg(any(feature = "openssl",
                         all(not(target_os = "macos"),
                             not(target_os = "windows"),
                             not(target_os = "ios"))))] {
-        fn verify_failed(err: &Error) {
-            assert!(format!("{}", err).contains("certificate verify failed"))
-        }
-
-        use verify_failed as assert_expired_error;
-        use verify_failed as assert_wrong_host;
-        use verify_failed as assert_self_signed;
-        use verify_failed as assert_untrusted_root;
+        const EXPIRED: ErrorKind = ErrorKind::CertificateExpired;
+        const WRONG_HOST: ErrorKind = ErrorKind::HostnameMismatch;
+        const SELF_SIGNED: ErrorKind = ErrorKind::SelfSigned;
+        const UNTRUSTED_ROOT: ErrorKind = ErrorKind::UntrustedRoot;
     } else if #[cfg(any(target_os = "macos", target_os = "ios"))] {
-
-        fn assert_invalid_cert_chain(err: &Error) {
-            assert!(format!("{}", err).contains("was not trusted."))
-        }
-
-        use crate::assert_invalid_cert_chain as assert_expired_error;
-        use crate::assert_invalid_cert_chain as assert_wrong_host;
-        use crate::assert_invalid_cert_chain as assert_self_signed;
-        use crate::assert_invalid_cert_chain as assert_untrusted_root;
+        // The security framework reports all of them as an untrusted chain.
+        const EXPIRED: ErrorKind = ErrorKind::InvalidCertificate;
+        const WRONG_HOST: ErrorKind = ErrorKind::InvalidCertificate;
+        const SELF_SIGNED: ErrorKind = ErrorKind::InvalidCertificate;
+        const UNTRUSTED_ROOT: ErrorKind = ErrorKind::InvalidCertificate;
     } else {
-        fn assert_expired_error(err: &Error) {
-            let s = err.to_string();
-            assert!(s.contains("system clock"), "error = {:?}", s);
-        }
-
-        fn assert_wrong_host(err: &Error) {
-            let s = err.to_string();
-            assert!(s.contains("CN name"), "error = {:?}", s);
-        }
-
-        fn assert_self_signed(err: &Error) {
-            let s = err.to_string();
-            assert!(s.contains("root certificate which is not trusted"), "error = {:?}", s);
-        }
-
-        use assert_self_signed as assert_untrusted_root;
+        const EXPIRED: ErrorKind = ErrorKind::CertificateExpired;
+        const WRONG_HOST: ErrorKind = ErrorKind::HostnameMismatch;
+        const SELF_SIGNED: ErrorKind = ErrorKind::UntrustedRoot;
+        const UNTRUSTED_ROOT: ErrorKind = ErrorKind::UntrustedRoot;
     }
 }
 
@@ -88,37 +75,36 @@ async fn get_host(host: &'static str) -> Error {
     let addr = t!(addr.to_socket_addrs()).next().unwrap();
 
     let socket = t!(TcpStream::connect(&addr).await);
-    let builder = TlsConnector::builder();
-    let cx = t!(builder.build());
-    let cx = tokio_tls::TlsConnector::from(cx);
-    let res = cx
-        .connect(host, socket)
-        .await
-        .map_err(|e| Error::new(io::ErrorKind::Other, e));
+    let cx = connector();
+    let res = cx.connect(host, socket).await;
 
     assert!(res.is_err());
     res.err().unwrap()
 }
 
+fn assert_kind(err: &Error, kind: ErrorKind) {
+    assert_eq!(err.kind(), kind, "bad error: {}", err);
+}
+
 #[tokio::test]
 async fn expired() {
-    assert_expired_error(&get_host("expired.badssl.com").await)
+    assert_kind(&get_host("expired.badssl.com").await, EXPIRED)
 }
 
 // TODO: the OSX builders on Travis apparently fail this tests spuriously?
 //       passes locally though? Seems... bad!
 #[tokio::test]
-#[cfg_attr(all(target_os = "macos", feature = "force-openssl"), ignore)]
+#[cfg_attr(all(target_os = "macos", feature = "openssl"), ignore)]
 async fn wrong_host() {
-    assert_wrong_host(&get_host("wrong.host.badssl.com").await)
+    assert_kind(&get_host("wrong.host.badssl.com").await, WRONG_HOST)
 }
 
 #[tokio::test]
 async fn self_signed() {
-    assert_self_signed(&get_host("self-signed.badssl.com").await)
+    assert_kind(&get_host("self-signed.badssl.com").await, SELF_SIGNED)
 }
 
 #[tokio::test]
 async fn untrusted_root() {
-    assert_untrusted_root(&get_host("untrusted-root.badssl.com").await)
+    assert_kind(&get_host("untrusted-root.badssl.com").await, UNTRUSTED_ROOT)
 }
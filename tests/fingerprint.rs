@@ -0,0 +1,104 @@
+#![warn(rust_2018_idioms)]
+
+//! Checks the JA3 and JA4 fingerprints of ClientHello messages captured in
+//! `tests/client_hello`.
+//!
+//! `ja3-example.bin` and `ja4-example.bin` were built to match the examples
+//! of the JA3 and JA4 documentation, the latter a Chrome ClientHello with
+//! GREASE values. The other captures were recorded from `curl` 7.88 and
+//! `openssl s_client` 3.5, except for `grease-sigalg.bin`, which is
+//! `ja4-example.bin` with a GREASE value added to its signature algorithms
+//! and so has the same fingerprints.
+
+use tokio_tls::ClientHello;
+
+struct Vector {
+    capture: &'static [u8],
+    ja3_string: &'static str,
+    ja3: &'static str,
+    ja4: &'static str,
+}
+
+const VECTORS: &[Vector] = &[
+    Vector {
+        capture: include_bytes!("client_hello/ja3-example.bin"),
+        ja3_string: "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0",
+        ja3: "ada70206e40642a3e4461f35503241d5",
+        ja4: "t10d120300_d94e65cdb899_33a13ba74d1c",
+    },
+    Vector {
+        capture: include_bytes!("client_hello/ja4-example.bin"),
+        ja3_string: "771,4865-4866-4867-49195-49199-49196-49200-52393-52392-49171-49172-156-157-47-53,\
+                     0-23-65281-10-11-35-16-5-13-18-51-45-43-27-17513-21,29-23-24,0",
+        ja3: "cd08e31494f9531f560d64c695473da9",
+        ja4: "t13d1516h2_8daaf6152771_e5627efa2ab1",
+    },
+    Vector {
+        capture: include_bytes!("client_hello/curl.bin"),
+        ja3_string: "771,4866-4867-4865-49196-49200-159-52393-52392-52394-49195-49199-158-49188-\
+                     49192-107-49187-49191-103-49162-49172-57-49161-49171-51-157-156-61-60-53-47-255,\
+                     0-11-10-16-22-23-49-13-43-45-51-21,29-23-30-25-24-256-257-258-259-260,0-1-2",
+        ja3: "0149f47eabf9a20d0893e2a44e5a6323",
+        ja4: "t13d3112h2_e8f1e7e78f70_b26ce05bbdd6",
+    },
+    Vector {
+        capture: include_bytes!("client_hello/openssl-s_client.bin"),
+        ja3_string: "771,4866-4867-4865-49196-49200-159-52393-52392-52394-49195-49199-158-49188-\
+                     49192-107-49187-49191-103-49162-49172-57-49161-49171-51-157-156-61-60-53-47,\
+                     65281-0-11-10-35-16-22-23-13-43-45-51,4588-29-23-30-24-25-256-257,0-1-2",
+        ja3: "bb4bfd0ec15f5be2c4225880b93a8b18",
+        ja4: "t13d3012h2_1d37bd780c83_8e6e362c5eac",
+    },
+    Vector {
+        capture: include_bytes!("client_hello/openssl-s_client-tls12.bin"),
+        ja3_string: "771,49196-49200-159-52393-52392-52394-49195-49199-158-49188-49192-107-49187-\
+                     49191-103-49162-49172-57-49161-49171-51-157-156-61-60-53-47,\
+                     65281-11-10-35-22-23-13,29-23-30-24-25,0-1-2",
+        ja3: "d49c326cc51a0b8d6eb7fb2f9adf137f",
+        ja4: "t12i270700_a2460661a67a_36cef8aed422",
+    },
+    Vector {
+        capture: include_bytes!("client_hello/grease-sigalg.bin"),
+        ja3_string: "771,4865-4866-4867-49195-49199-49196-49200-52393-52392-49171-49172-156-157-47-53,\
+                     0-23-65281-10-11-35-16-5-13-18-51-45-43-27-17513-21,29-23-24,0",
+        ja3: "cd08e31494f9531f560d64c695473da9",
+        ja4: "t13d1516h2_8daaf6152771_e5627efa2ab1",
+    },
+];
+
+#[test]
+fn vectors() {
+    for vector in VECTORS {
+        let hello = ClientHello::from_bytes(vector.capture).unwrap();
+        assert_eq!(hello.ja3_string(), vector.ja3_string);
+        assert_eq!(hello.ja3(), vector.ja3);
+        assert_eq!(hello.ja4(), vector.ja4);
+    }
+}
+
+#[test]
+fn parsed_fields() {
+    let hello = ClientHello::from_bytes(VECTORS[1].capture).unwrap();
+    assert_eq!(hello.server_name(), Some("example.com"));
+    assert_eq!(
+        hello.alpn_protocols(),
+        &[b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+    assert_eq!(hello.supported_versions(), &[0x5a5a, 0x0304, 0x0303]);
+    assert_eq!(hello.cipher_suites()[0], 0x0a0a);
+
+    let hello = ClientHello::from_bytes(VECTORS[4].capture).unwrap();
+    assert_eq!(hello.server_name(), None);
+    assert!(hello.alpn_protocols().is_empty());
+    assert!(hello.supported_versions().is_empty());
+
+    let hello = ClientHello::from_bytes(VECTORS[5].capture).unwrap();
+    assert_eq!(hello.signature_algorithms()[0], 0x1a1a);
+}
+
+#[test]
+fn incomplete_captures() {
+    let capture = VECTORS[2].capture;
+    assert!(ClientHello::from_bytes(&capture[..capture.len() - 1]).is_none());
+    assert!(ClientHello::from_bytes(b"GET / HTTP/1.1\r\n\r\n").is_none());
+}
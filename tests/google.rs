@@ -2,8 +2,6 @@
 
 use cfg_if::cfg_if;
 use env_logger;
-use native_tls;
-use native_tls::TlsConnector;
 use std::io;
 use std::net::ToSocketAddrs;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -19,31 +17,54 @@ macro_rules! t {
     };
 }
 
+#[cfg(feature = "rustls")]
+fn connector() -> tokio_tls::TlsConnector {
+    let mut config = tokio_tls::rustls::ClientConfig::new();
+    config
+        .root_store
+        .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    std::sync::Arc::new(config).into()
+}
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+fn connector() -> tokio_tls::TlsConnector {
+    use tokio_tls::openssl::ssl::{SslConnector, SslMethod};
+
+    let builder = t!(SslConnector::builder(SslMethod::tls()));
+    builder.build().into()
+}
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+fn connector() -> tokio_tls::TlsConnector {
+    let builder = native_tls::TlsConnector::builder();
+    t!(builder.build()).into()
+}
+
 cfg_if! {
-    if #[cfg(feature = "force-rustls")] {
+    if #[cfg(feature = "rustls")] {
         fn assert_bad_hostname_error(err: &io::Error) {
             let err = err.to_string();
             assert!(err.contains("CertNotValidForName"), "bad error: {}", err);
         }
-    } else if #[cfg(any(feature = "force-openssl",
+    } else if #[cfg(any(feature = "openssl",
                         all(not(target_os = "macos"),
                             not(target_os = "windows"),
                             not(target_os = "ios"))))] {
         fn assert_bad_hostname_error(err: &io::Error) {
             let err = err.get_ref().unwrap();
-            let err = err.downcast_ref::<native_tls::Error>().unwrap();
+            let err = err.downcast_ref::<tokio_tls::Error>().unwrap();
             assert!(format!("{}", err).contains("certificate verify failed"));
         }
     } else if #[cfg(any(target_os = "macos", target_os = "ios"))] {
         fn assert_bad_hostname_error(err: &io::Error) {
             let err = err.get_ref().unwrap();
-            let err = err.downcast_ref::<native_tls::Error>().unwrap();
+            let err = err.downcast_ref::<tokio_tls::Error>().unwrap();
             assert!(format!("{}", err).contains("was not trusted."));
         }
     } else {
         fn assert_bad_hostname_error(err: &io::Error) {
             let err = err.get_ref().unwrap();
-            let err = err.downcast_ref::<native_tls::Error>().unwrap();
+            let err = err.downcast_ref::<tokio_tls::Error>().unwrap();
             assert!(format!("{}", err).contains("CN name"));
         }
     }
@@ -60,9 +81,7 @@ async fn fetch_google() {
 
     // Send off the request by first negotiating an SSL handshake, then writing
     // of our request, then flushing, then finally read off the response.
-    let builder = TlsConnector::builder();
-    let connector = t!(builder.build());
-    let connector = tokio_tls::TlsConnector::from(connector);
+    let connector = connector();
     let mut socket = t!(connector.connect("google.com", socket).await);
     t!(socket.write_all(b"GET / HTTP/1.0\r\n\r\n").await);
     let mut data = Vec::new();
@@ -76,12 +95,12 @@ async fn fetch_google() {
     assert!(data.ends_with("</html>") || data.ends_with("</HTML>"));
 }
 
-fn native2io(e: native_tls::Error) -> io::Error {
+fn tls2io(e: tokio_tls::Error) -> io::Error {
     io::Error::new(io::ErrorKind::Other, e)
 }
 
 // see comment in bad.rs for ignore reason
-#[cfg_attr(all(target_os = "macos", feature = "force-openssl"), ignore)]
+#[cfg_attr(all(target_os = "macos", feature = "openssl"), ignore)]
 #[tokio::test]
 async fn wrong_hostname_error() {
     drop(env_logger::try_init());
@@ -89,13 +108,11 @@ async fn wrong_hostname_error() {
     let addr = t!("google.com:443".to_socket_addrs()).next().unwrap();
 
     let socket = t!(TcpStream::connect(&addr).await);
-    let builder = TlsConnector::builder();
-    let connector = t!(builder.build());
-    let connector = tokio_tls::TlsConnector::from(connector);
+    let connector = connector();
     let res = connector
         .connect("rust-lang.org", socket)
         .await
-        .map_err(native2io);
+        .map_err(tls2io);
 
     assert!(res.is_err());
     assert_bad_hostname_error(&res.err().unwrap());
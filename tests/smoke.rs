@@ -1,18 +1,23 @@
 #![warn(rust_2018_idioms)]
 
+use bytes::buf::{Buf, BufExt};
 use cfg_if::cfg_if;
 use env_logger;
 use futures::join;
-use native_tls;
-use native_tls::{Identity, TlsAcceptor, TlsConnector};
 use std::io::Write;
 use std::marker::Unpin;
+use std::pin::Pin;
 use std::process::Command;
 use std::ptr;
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::stream::StreamExt;
 use tokio_tls;
+use tokio_tls::starttls;
 
 macro_rules! t {
     ($e:expr) => {
@@ -124,111 +129,235 @@ fn openssl_keys() -> &'static Keys {
     unsafe { &*KEYS }
 }
 
+/// A client certificate and the CA certificate it is issued by.
+#[allow(dead_code)]
+struct ClientKeys {
+    ca_der: Vec<u8>,
+    cert_der: Vec<u8>,
+    pkey_der: Vec<u8>,
+}
+
+#[allow(dead_code)]
+fn client_keys() -> &'static ClientKeys {
+    static INIT: Once = Once::new();
+    static mut KEYS: *mut ClientKeys = ptr::null_mut();
+
+    fn openssl(args: &[&dyn AsRef<std::ffi::OsStr>]) -> Vec<u8> {
+        let output = t!(Command::new("openssl").args(args).output());
+        assert!(output.status.success(), "{:?}", output);
+        output.stdout
+    }
+
+    INIT.call_once(|| {
+        let path = t!(env::current_exe());
+        let path = path.parent().unwrap();
+        let ca_key = path.join("client-ca.key");
+        let ca_cert = path.join("client-ca.crt");
+        let key = path.join("client.key");
+        let csr = path.join("client.csr");
+        let cert = path.join("client.crt");
+        let config = path.join("client.config");
+
+        t!(t!(File::create(&config)).write_all(
+            b"\
+            [req]\n\
+            distinguished_name=dn\n\
+            [dn]\n\
+            [ca]\n\
+            basicConstraints=critical,CA:TRUE\n\
+            keyUsage=critical,keyCertSign\n\
+            [client]\n\
+            basicConstraints=CA:FALSE\n\
+            extendedKeyUsage=clientAuth\n\
+        ",
+        ));
+
+        openssl(&[
+            &"req",
+            &"-nodes",
+            &"-x509",
+            &"-newkey",
+            &"rsa:2048",
+            &"-config",
+            &config,
+            &"-extensions",
+            &"ca",
+            &"-subj",
+            &"/CN=client CA",
+            &"-keyout",
+            &ca_key,
+            &"-out",
+            &ca_cert,
+            &"-days",
+            &"1",
+        ]);
+        openssl(&[
+            &"req",
+            &"-new",
+            &"-nodes",
+            &"-newkey",
+            &"rsa:2048",
+            &"-config",
+            &config,
+            &"-subj",
+            &"/CN=client",
+            &"-keyout",
+            &key,
+            &"-out",
+            &csr,
+        ]);
+        openssl(&[
+            &"x509",
+            &"-req",
+            &"-in",
+            &csr,
+            &"-CA",
+            &ca_cert,
+            &"-CAkey",
+            &ca_key,
+            &"-set_serial",
+            &"1",
+            &"-extfile",
+            &config,
+            &"-extensions",
+            &"client",
+            &"-days",
+            &"1",
+            &"-out",
+            &cert,
+        ]);
+
+        let keys = Box::new(ClientKeys {
+            ca_der: openssl(&[&"x509", &"-outform", &"der", &"-in", &ca_cert]),
+            cert_der: openssl(&[&"x509", &"-outform", &"der", &"-in", &cert]),
+            pkey_der: openssl(&[&"rsa", &"-outform", &"der", &"-in", &key]),
+        });
+        unsafe {
+            KEYS = Box::into_raw(keys);
+        }
+    });
+    unsafe { &*KEYS }
+}
+
+/// Returns the base64 encoded SHA-256 digest of the public key of the PEM
+/// certificate in `file`, as computed by the OpenSSL command line tool.
+#[allow(dead_code)]
+fn openssl_pin(file: &std::path::Path) -> String {
+    fn openssl(args: &[&dyn AsRef<std::ffi::OsStr>]) -> Vec<u8> {
+        let output = t!(Command::new("openssl").args(args).output());
+        assert!(output.status.success(), "{:?}", output);
+        output.stdout
+    }
+
+    let pem = file.with_extension("pub.pem");
+    let der = file.with_extension("pub.der");
+    let digest = file.with_extension("pin");
+    t!(std::fs::write(
+        &pem,
+        openssl(&[&"x509", &"-in", &file, &"-pubkey", &"-noout"])
+    ));
+    t!(std::fs::write(
+        &der,
+        openssl(&[&"pkey", &"-pubin", &"-in", &pem, &"-outform", &"der"])
+    ));
+    t!(std::fs::write(
+        &digest,
+        openssl(&[&"dgst", &"-sha256", &"-binary", &der])
+    ));
+    let pin = openssl(&[&"base64", &"-in", &digest]);
+    t!(String::from_utf8(pin)).trim().to_owned()
+}
+
 cfg_if! {
     if #[cfg(feature = "rustls")] {
-        use webpki;
-        use untrusted;
         use std::env;
         use std::fs::File;
-        use std::process::Command;
         use std::sync::Once;
 
-        use untrusted::Input;
-        use webpki::trust_anchor_util;
+        use tokio_tls::rustls::{
+            Certificate, ClientConfig, NoClientAuth, NoServerSessionStorage, PrivateKey,
+            ServerConfig,
+        };
+
+        fn contexts() -> (tokio_tls::TlsAcceptor, tokio_tls::TlsConnector) {
+            let keys = openssl_keys();
+            let cert = Certificate(keys.cert_der.clone());
 
-        fn server_cx() -> io::Result<ServerContext> {
-            let mut cx = ServerContext::new();
+            // Without session storage, TLS 1.3 servers don't send session
+            // tickets. The tests don't read them on the client side, and
+            // closing a socket with unread data resets the connection.
+            let mut srv = ServerConfig::new(NoClientAuth::new());
+            srv.session_storage = Arc::new(NoServerSessionStorage {});
+            t!(srv.set_single_cert(vec![cert.clone()], PrivateKey(keys.pkey_der.clone())));
 
-            let (cert, key) = keys();
-            cx.config_mut()
-              .set_single_cert(vec![cert.to_vec()], key.to_vec());
+            let mut client = ClientConfig::new();
+            t!(client.root_store.add(&cert));
 
-            Ok(cx)
+            (Arc::new(srv).into(), Arc::new(client).into())
         }
 
-        fn configure_client(cx: &mut ClientContext) {
-            let (cert, _key) = keys();
-            let cert = Input::from(cert);
-            let anchor = trust_anchor_util::cert_der_as_trust_anchor(cert).unwrap();
-            cx.config_mut().root_store.add_trust_anchors(&[anchor]);
+        /// Returns a connector which authenticates with the certificate and
+        /// private key in `identity`, if any.
+        fn client_connector(identity: Option<(&[u8], &[u8])>) -> tokio_tls::TlsConnector {
+            let keys = openssl_keys();
+            let mut client = ClientConfig::new();
+            t!(client.root_store.add(&Certificate(keys.cert_der.clone())));
+            if let Some((cert, pkey)) = identity {
+                let cert = Certificate(cert.to_vec());
+                t!(client.set_single_client_cert(vec![cert], PrivateKey(pkey.to_vec())));
+            }
+            Arc::new(client).into()
         }
+    } else if #[cfg(feature = "openssl")] {
+        use std::env;
+        use std::fs::File;
+        use std::sync::Once;
 
-        // Like OpenSSL we generate certificates on the fly, but for OSX we
-        // also have to put them into a specific keychain. We put both the
-        // certificates and the keychain next to our binary.
-        //
-        // Right now I don't know of a way to programmatically create a
-        // self-signed certificate, so we just fork out to the `openssl` binary.
-        fn keys() -> (&'static [u8], &'static [u8]) {
-            static INIT: Once = Once::new();
-            static mut KEYS: *mut (Vec<u8>, Vec<u8>) = ptr::null_mut();
+        use tokio_tls::openssl::pkey::PKey;
+        use tokio_tls::openssl::ssl::{SslAcceptor, SslConnector, SslMethod};
+        use tokio_tls::openssl::x509::X509;
 
-            INIT.call_once(|| {
-                let (key, cert) = openssl_keys();
-                let path = t!(env::current_exe());
-                let path = path.parent().unwrap();
-                let keyfile = path.join("test.key");
-                let certfile = path.join("test.crt");
-                let config = path.join("openssl.config");
-
-                File::create(&config).unwrap().write_all(b"\
-                    [req]\n\
-                    distinguished_name=dn\n\
-                    [ dn ]\n\
-                    CN=localhost\n\
-                    [ ext ]\n\
-                    basicConstraints=CA:FALSE,pathlen:0\n\
-                    subjectAltName = @alt_names
-                    [alt_names]
-                    DNS.1 = localhost
-                ").unwrap();
-
-                let subj = "/C=US/ST=Denial/L=Sprintfield/O=Dis/CN=localhost";
-                let output = t!(Command::new("openssl")
-                                        .arg("req")
-                                        .arg("-nodes")
-                                        .arg("-x509")
-                                        .arg("-newkey").arg("rsa:2048")
-                                        .arg("-config").arg(&config)
-                                        .arg("-extensions").arg("ext")
-                                        .arg("-subj").arg(subj)
-                                        .arg("-keyout").arg(&keyfile)
-                                        .arg("-out").arg(&certfile)
-                                        .arg("-days").arg("1")
-                                        .output());
-                assert!(output.status.success());
-
-                let crtout = t!(Command::new("openssl")
-                                        .arg("x509")
-                                        .arg("-outform").arg("der")
-                                        .arg("-in").arg(&certfile)
-                                        .output());
-                assert!(crtout.status.success());
-                let keyout = t!(Command::new("openssl")
-                                        .arg("rsa")
-                                        .arg("-outform").arg("der")
-                                        .arg("-in").arg(&keyfile)
-                                        .output());
-                assert!(keyout.status.success());
-
-                let cert = crtout.stdout;
-                let key = keyout.stdout;
-                unsafe {
-                    KEYS = Box::into_raw(Box::new((cert, key)));
-                }
-            });
-            unsafe {
-                (&(*KEYS).0, &(*KEYS).1)
+        fn contexts() -> (tokio_tls::TlsAcceptor, tokio_tls::TlsConnector) {
+            let keys = openssl_keys();
+            let cert = t!(X509::from_der(&keys.cert_der));
+            let pkey = t!(PKey::private_key_from_der(&keys.pkey_der));
+
+            // As with rustls, don't send TLS 1.3 session tickets which the
+            // client would never read.
+            let mut srv = t!(SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()));
+            t!(srv.set_certificate(&cert));
+            t!(srv.set_private_key(&pkey));
+            t!(srv.set_num_tickets(0));
+
+            let mut client = t!(SslConnector::builder(SslMethod::tls()));
+            t!(client.cert_store_mut().add_cert(cert));
+
+            (srv.build().into(), client.build().into())
+        }
+
+        /// Returns a connector which authenticates with the certificate and
+        /// private key in `identity`, if any.
+        fn client_connector(identity: Option<(&[u8], &[u8])>) -> tokio_tls::TlsConnector {
+            let keys = openssl_keys();
+            let mut client = t!(SslConnector::builder(SslMethod::tls()));
+            t!(client.cert_store_mut().add_cert(t!(X509::from_der(&keys.cert_der))));
+            if let Some((cert, pkey)) = identity {
+                let cert = t!(X509::from_der(cert));
+                let pkey = t!(PKey::private_key_from_der(pkey));
+                t!(client.set_certificate(&cert));
+                t!(client.set_private_key(&pkey));
             }
+            client.build().into()
         }
-    } else if #[cfg(any(feature = "force-openssl",
-                        all(not(target_os = "macos"),
-                            not(target_os = "windows"),
-                            not(target_os = "ios"))))] {
+    } else if #[cfg(all(not(target_os = "macos"),
+                        not(target_os = "windows"),
+                        not(target_os = "ios")))] {
         use std::fs::File;
         use std::env;
         use std::sync::Once;
 
+        use native_tls::{Identity, TlsAcceptor, TlsConnector};
+
         fn contexts() -> (tokio_tls::TlsAcceptor, tokio_tls::TlsConnector) {
             let keys = openssl_keys();
 
@@ -247,6 +376,8 @@ cfg_if! {
         use std::fs::File;
         use std::sync::Once;
 
+        use native_tls::{Identity, TlsAcceptor, TlsConnector};
+
         fn contexts() -> (tokio_tls::TlsAcceptor, tokio_tls::TlsConnector) {
             let keys = openssl_keys();
 
@@ -269,6 +400,8 @@ cfg_if! {
         use std::mem;
         use std::sync::Once;
 
+        use native_tls::{Identity, TlsAcceptor, TlsConnector};
+
         use schannel::cert_context::CertContext;
         use schannel::cert_store::{CertStore, CertAdd, Memory};
         use winapi::shared::basetsd::*;
@@ -516,6 +649,7 @@ async fn copy_data<W: AsyncWrite + Unpin>(mut w: W) -> Result<usize, Error> {
 
         println!("remaining: {}", data.len());
     }
+    w.shutdown().await?;
     Ok(amt)
 }
 
@@ -627,3 +761,1417 @@ async fn one_byte_at_a_time() {
     assert_eq!(amt, AMT);
     assert!(data == vec![9; AMT as usize]);
 }
+
+#[tokio::test]
+async fn peer_certificate() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let socket = t!(server_cx.accept(socket).await);
+        t!(socket.peer_certificate())
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let socket = t!(client_cx.connect("localhost", socket).await);
+        let cert = t!(socket.peer_certificate());
+        let chain = t!(socket.peer_certificate_chain());
+        (cert, chain)
+    };
+
+    let (server_peer, (cert, chain)) = join!(server, client);
+    assert!(server_peer.is_none());
+
+    let cert = cert.unwrap();
+    if cfg!(any(feature = "openssl", feature = "rustls")) {
+        assert_eq!(chain.unwrap()[0], cert);
+    } else {
+        assert!(chain.is_none());
+    }
+
+    let parsed = t!(cert.parse());
+    assert!(
+        parsed.subject().contains("CN=localhost"),
+        "{}",
+        parsed.subject()
+    );
+    assert!(parsed.not_before() < parsed.not_after());
+    #[cfg(not(windows))]
+    assert!(parsed
+        .subject_alt_names()
+        .contains(&tokio_tls::SubjectAltName::Dns("localhost".to_string())));
+}
+
+#[cfg(all(
+    feature = "alpn",
+    not(any(target_os = "macos", target_os = "ios", windows))
+))]
+#[tokio::test]
+async fn alpn() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let keys = openssl_keys();
+    let pkcs12 = t!(native_tls::Identity::from_pkcs12(
+        &keys.pkcs12_der,
+        "foobar"
+    ));
+    let server_cx = t!(native_tls::TlsAcceptor::builder(pkcs12)
+        .accept_alpn(&["h2", "http/1.1"])
+        .build());
+    let server_cx = tokio_tls::TlsAcceptor::from(server_cx);
+
+    let cert = t!(native_tls::Certificate::from_der(&keys.cert_der));
+    let client_cx = t!(native_tls::TlsConnector::builder()
+        .add_root_certificate(cert)
+        .request_alpns(&["http/1.1"])
+        .build());
+    let client_cx = tokio_tls::TlsConnector::from(client_cx);
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let socket = t!(server_cx.accept(socket).await);
+        t!(socket.negotiated_alpn())
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let socket = t!(client_cx.connect("localhost", socket).await);
+        t!(socket.negotiated_alpn())
+    };
+
+    let (server, client) = join!(server, client);
+    assert_eq!(server.as_deref(), Some(&b"http/1.1"[..]));
+    assert_eq!(client.as_deref(), Some(&b"http/1.1"[..]));
+}
+
+#[tokio::test]
+async fn handshake_timeout() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (mut server_cx, _) = contexts();
+    server_cx.set_handshake_timeout(Some(Duration::from_millis(100)));
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        match server_cx.accept(socket).await {
+            Ok(_) => panic!("handshake with a stalled client succeeded"),
+            Err(e) => e,
+        }
+    };
+
+    // Connect, but never start the handshake. The server should give up and
+    // close the connection.
+    let client = async move {
+        let mut socket = t!(TcpStream::connect(&addr).await);
+        let mut buf = Vec::new();
+        t!(socket.read_to_end(&mut buf).await);
+    };
+
+    let (err, ()) = join!(server, client);
+    assert!(err.is_timeout(), "{:?}", err);
+    assert_eq!(err.kind(), tokio_tls::ErrorKind::Timeout);
+}
+
+#[cfg(any(
+    feature = "openssl",
+    feature = "rustls",
+    not(any(target_os = "macos", target_os = "windows", target_os = "ios"))
+))]
+#[tokio::test]
+async fn error_kinds() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    cfg_if! {
+        if #[cfg(feature = "rustls")] {
+            let untrusting = Arc::new(tokio_tls::rustls::ClientConfig::new()).into();
+            // webpki does not tell self-signed certificates apart.
+            let untrusted = tokio_tls::ErrorKind::UntrustedRoot;
+        } else if #[cfg(feature = "openssl")] {
+            let builder = t!(SslConnector::builder(SslMethod::tls()));
+            let untrusting = builder.build().into();
+            let untrusted = tokio_tls::ErrorKind::SelfSigned;
+        } else {
+            let untrusting = t!(TlsConnector::builder().build()).into();
+            let untrusted = tokio_tls::ErrorKind::SelfSigned;
+        }
+    }
+    let untrusting: tokio_tls::TlsConnector = untrusting;
+
+    // The server's side of the failed handshakes is not checked.
+    let server = async move {
+        let mut incoming = srv.incoming();
+        for _ in 0..2 {
+            let socket = t!(incoming.next().await.unwrap());
+            drop(server_cx.accept(socket).await);
+        }
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let wrong_host = client_cx.connect("example.com", socket).await.unwrap_err();
+        let socket = t!(TcpStream::connect(&addr).await);
+        let untrusted_root = untrusting.connect("localhost", socket).await.unwrap_err();
+        (wrong_host.kind(), untrusted_root.kind())
+    };
+
+    let ((), kinds) = join!(server, client);
+    assert_eq!(kinds, (tokio_tls::ErrorKind::HostnameMismatch, untrusted));
+}
+
+#[tokio::test]
+async fn handshake_futures_are_owned() {
+    fn assert_send_static<T: Send + 'static>(t: T) -> T {
+        t
+    }
+
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    // Both futures outlive the connector and acceptor that created them.
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let accept: tokio_tls::Accept<TcpStream> = server_cx.accept(socket);
+        drop(server_cx);
+        t!(assert_send_static(accept).await)
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let connect: tokio_tls::Connect<TcpStream> = client_cx.connect("localhost", socket);
+        drop(client_cx);
+        t!(assert_send_static(connect).await)
+    };
+
+    let (mut server, mut client) = join!(server, client);
+    let (_, data) = join!(
+        async move {
+            t!(server.write_all(b"hello").await);
+            t!(server.shutdown().await);
+        },
+        async move {
+            let mut data = Vec::new();
+            t!(client.read_to_end(&mut data).await);
+            data
+        }
+    );
+    assert_eq!(data, b"hello");
+}
+
+#[tokio::test]
+async fn split_halves() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    // Echo everything back from separate halves until the client is done.
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let socket = t!(server_cx.accept(socket).await);
+        let (mut reader, mut writer) = socket.into_split();
+        let amt = t!(tokio::io::copy(&mut reader, &mut writer).await);
+        t!(writer.shutdown().await);
+        assert!(reader.is_pair_of(&writer));
+        drop(reader.unsplit(writer));
+        amt
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let socket = t!(client_cx.connect("localhost", socket).await);
+        let (mut reader, writer) = socket.into_split();
+        let writer = tokio::spawn(async move {
+            let mut writer = writer;
+            let amt = t!(copy_data(&mut writer).await);
+            t!(writer.shutdown().await);
+            amt
+        });
+        let mut data = Vec::new();
+        t!(reader.read_to_end(&mut data).await);
+        (t!(writer.await), data)
+    };
+
+    let (amt, (written, data)) = join!(server, client);
+    assert_eq!(amt as usize, AMT);
+    assert_eq!(written, AMT);
+    assert!(data == vec![9; AMT]);
+}
+
+/// A transport which counts the TLS records written through it.
+struct RecordCounter<S> {
+    inner: S,
+    records: Arc<AtomicUsize>,
+    header: Vec<u8>,
+    remaining: usize,
+}
+
+impl<S> RecordCounter<S> {
+    fn new(inner: S) -> (RecordCounter<S>, Arc<AtomicUsize>) {
+        let records = Arc::new(AtomicUsize::new(0));
+        let counter = RecordCounter {
+            inner,
+            records: records.clone(),
+            header: Vec::new(),
+            remaining: 0,
+        };
+        (counter, records)
+    }
+
+    fn count(&mut self, mut buf: &[u8]) {
+        while !buf.is_empty() {
+            if self.remaining > 0 {
+                let n = self.remaining.min(buf.len());
+                self.remaining -= n;
+                buf = &buf[n..];
+                continue;
+            }
+            let n = (5 - self.header.len()).min(buf.len());
+            self.header.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+            if self.header.len() == 5 {
+                self.remaining = usize::from(u16::from_be_bytes([self.header[3], self.header[4]]));
+                self.header.clear();
+                self.records.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordCounter<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordCounter<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.count(&buf[..n]);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn vectored_writes() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(server_cx.accept(socket).await);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+        data
+    };
+
+    // Returns the number of records it took to write `header` and `body`,
+    // either chained into a single `Buf` or one after the other.
+    async fn records<S>(
+        socket: &mut tokio_tls::TlsStream<RecordCounter<S>>,
+        records: &AtomicUsize,
+        header: &[u8],
+        body: &[u8],
+        vectored: bool,
+    ) -> usize
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let before = records.load(Ordering::SeqCst);
+        if vectored {
+            let mut buf = BufExt::chain(header, body);
+            while buf.has_remaining() {
+                t!(socket.write_buf(&mut buf).await);
+            }
+        } else {
+            t!(socket.write_all(header).await);
+            t!(socket.write_all(body).await);
+        }
+        t!(socket.flush().await);
+        records.load(Ordering::SeqCst) - before
+    }
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let (socket, counter) = RecordCounter::new(socket);
+        let mut socket = t!(client_cx.connect("localhost", socket).await);
+
+        let header = [1; 200];
+        let body = [2; 1000];
+        let large = vec![3; 40 * 1024];
+        let counts = (
+            records(&mut socket, &counter, &header, &body, false).await,
+            records(&mut socket, &counter, &header, &body, true).await,
+            records(&mut socket, &counter, &header, &large, false).await,
+            records(&mut socket, &counter, &header, &large, true).await,
+        );
+        t!(socket.shutdown().await);
+        counts
+    };
+
+    let (data, counts) = join!(server, client);
+    assert_eq!(counts, (2, 1, 4, 3));
+    assert_eq!(data.len(), 2 * (200 + 1000) + 2 * (200 + 40 * 1024));
+}
+
+#[tokio::test]
+async fn truncation() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+    let mut lenient_cx = client_cx.clone();
+    lenient_cx.set_allow_truncation(true);
+    assert!(!client_cx.allow_truncation());
+
+    // The first connection is closed properly, the other two are cut off
+    // without a close_notify.
+    let server = async move {
+        let mut incoming = srv.incoming();
+        for close in &[true, false, false] {
+            let socket = t!(incoming.next().await.unwrap());
+            let mut socket = t!(server_cx.accept(socket).await);
+            t!(socket.write_all(b"hello").await);
+            t!(socket.flush().await);
+            if *close {
+                t!(socket.shutdown().await);
+            }
+        }
+    };
+
+    let client = async move {
+        let mut results = Vec::new();
+        for cx in &[&client_cx, &client_cx, &lenient_cx] {
+            let socket = t!(TcpStream::connect(&addr).await);
+            let mut socket = t!(cx.connect("localhost", socket).await);
+            let mut data = Vec::new();
+            let res = socket.read_to_end(&mut data).await;
+            results.push((res.map_err(|e| e.kind()), data));
+        }
+        results
+    };
+
+    let (_, results) = join!(server, client);
+    assert_eq!(results[0], (Ok(5), b"hello".to_vec()));
+    assert_eq!(
+        results[1],
+        (Err(ErrorKind::UnexpectedEof), b"hello".to_vec())
+    );
+    assert_eq!(results[2], (Ok(5), b"hello".to_vec()));
+}
+
+#[tokio::test]
+async fn into_inner() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    // The client closes the session first. The server reads up to the
+    // client's close_notify, closes its end and continues in plaintext,
+    // which may or may not be read along with its close_notify.
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(server_cx.accept(socket).await);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+        assert_eq!(data, b"hello");
+        let (mut socket, rest) = t!(socket.into_inner().await);
+        assert!(rest.is_empty());
+        t!(socket.write_all(b"plaintext").await);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+        data
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket = t!(client_cx.connect("localhost", socket).await);
+        t!(socket.write_all(b"hello").await);
+        let (mut socket, mut data) = t!(socket.into_inner().await);
+        t!(socket.write_all(b"bye").await);
+        t!(socket.shutdown(std::net::Shutdown::Write));
+        t!(socket.read_to_end(&mut data).await);
+        data
+    };
+
+    let (server_data, client_data) = join!(server, client);
+    assert_eq!(server_data, b"bye");
+    assert_eq!(client_data, b"plaintext");
+}
+
+#[tokio::test]
+async fn starttls_smtp() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(starttls::smtp_server(&server_cx, "localhost", socket).await);
+        t!(socket.write_all(b"250 hello").await);
+        t!(socket.shutdown().await);
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket =
+            t!(starttls::smtp_client(&client_cx, "localhost", "client.localhost", socket).await);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+        data
+    };
+
+    let (_, data) = join!(server, client);
+    assert_eq!(data, b"250 hello");
+}
+
+#[tokio::test]
+async fn starttls_imap() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(starttls::imap_server(&server_cx, "localhost", socket).await);
+        t!(socket.write_all(b"* OK hello").await);
+        t!(socket.shutdown().await);
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket = t!(starttls::imap_client(&client_cx, "localhost", socket).await);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+        data
+    };
+
+    let (_, data) = join!(server, client);
+    assert_eq!(data, b"* OK hello");
+}
+
+/// A transport which sends a plaintext command in front of the first data
+/// written through it, and skips the plaintext reply to it when reading.
+struct Pipelined<S> {
+    inner: S,
+    command: Vec<u8>,
+    reply: usize,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Pipelined<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        while self.reply > 0 {
+            let mut skip = vec![0; self.reply];
+            match Pin::new(&mut self.inner).poll_read(cx, &mut skip) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Ok(n)) => self.reply -= n,
+                res => return res,
+            }
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Pipelined<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        if self.command.is_empty() {
+            return Pin::new(&mut self.inner).poll_write(cx, buf);
+        }
+        let mut data = self.command.clone();
+        data.extend_from_slice(buf);
+        let res = Pin::new(&mut self.inner).poll_write(cx, &data);
+        if let Poll::Ready(Ok(n)) = res {
+            assert_eq!(n, data.len(), "partial write of the pipelined command");
+            self.command.clear();
+            return Poll::Ready(Ok(buf.len()));
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn starttls_pipelined() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(starttls::imap_server(&server_cx, "localhost", socket).await);
+        t!(socket.write_all(b"* OK hello").await);
+        t!(socket.shutdown().await);
+    };
+
+    // The client sends its ClientHello along with the STARTTLS command,
+    // so that the server reads it into its line buffer.
+    let client = async move {
+        let mut socket = t!(TcpStream::connect(&addr).await);
+        let mut greeting = Vec::new();
+        while !greeting.ends_with(b"\r\n") {
+            let mut buf = [0; 1];
+            t!(socket.read_exact(&mut buf).await);
+            greeting.push(buf[0]);
+        }
+        let socket = Pipelined {
+            inner: socket,
+            command: b"a1 STARTTLS\r\n".to_vec(),
+            reply: b"a1 OK Begin TLS negotiation now\r\n".len(),
+        };
+        let mut socket = t!(client_cx.connect("localhost", socket).await);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+        data
+    };
+
+    let (_, data) = join!(server, client);
+    assert_eq!(data, b"* OK hello");
+}
+
+#[tokio::test]
+async fn accept_or_plaintext() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    // Echoes one request per connection, telling whether it came over TLS.
+    let server = async move {
+        let mut incoming = srv.incoming();
+        for _ in 0..2 {
+            let socket = t!(incoming.next().await.unwrap());
+            let mut socket = t!(server_cx.accept_or_plaintext(socket).await);
+            let tls = match socket {
+                tokio_tls::MaybeTlsStream::Tls(_) => "tls",
+                tokio_tls::MaybeTlsStream::Plaintext(_) => "plaintext",
+            };
+            let mut data = vec![0; 5];
+            t!(socket.read_exact(&mut data).await);
+            data.extend_from_slice(tls.as_bytes());
+            t!(socket.write_all(&data).await);
+            t!(socket.shutdown().await);
+        }
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket = t!(client_cx.connect("localhost", socket).await);
+        t!(socket.write_all(b"hello").await);
+        let mut tls = Vec::new();
+        t!(socket.read_to_end(&mut tls).await);
+
+        let mut socket = t!(TcpStream::connect(&addr).await);
+        t!(socket.write_all(b"hello").await);
+        let mut plaintext = Vec::new();
+        t!(socket.read_to_end(&mut plaintext).await);
+        (tls, plaintext)
+    };
+
+    let (_, (tls, plaintext)) = join!(server, client);
+    assert_eq!(tls, b"hellotls");
+    assert_eq!(plaintext, b"helloplaintext");
+}
+
+#[tokio::test]
+async fn client_hello_hook() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (mut server_cx, client_cx) = contexts();
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let seen2 = seen.clone();
+    server_cx.set_client_hello_hook(move |hello: &tokio_tls::ClientHello| {
+        let mut seen = seen2.lock().unwrap();
+        seen.push(hello.clone());
+        // Only the first connection is let through.
+        if seen.len() > 1 {
+            return Err(Error::new(ErrorKind::PermissionDenied, "rejected by hook").into());
+        }
+        Ok(())
+    });
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(server_cx.accept(socket).await);
+        assert_eq!(socket.server_name(), Some("localhost"));
+        t!(socket.write_all(b"hello").await);
+        t!(socket.shutdown().await);
+
+        let socket = t!(incoming.next().await.unwrap());
+        let err = server_cx.accept(socket).await.unwrap_err();
+        assert_eq!(err.to_string(), "rejected by hook");
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket = t!(client_cx.connect("localhost", socket).await);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+        assert_eq!(data, b"hello");
+
+        let socket = t!(TcpStream::connect(&addr).await);
+        assert!(client_cx.connect("localhost", socket).await.is_err());
+    };
+
+    join!(server, client);
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    let hello = &seen[0];
+    assert_eq!(hello.server_name(), Some("localhost"));
+    assert_eq!(hello.legacy_version(), 0x0303);
+    assert!(!hello.cipher_suites().is_empty());
+    assert!(hello.extensions().contains(&0));
+    assert!(hello.alpn_protocols().is_empty());
+}
+
+#[tokio::test]
+async fn fingerprints() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (mut server_cx, client_cx) = contexts();
+    server_cx.set_fingerprint_clients(true);
+    let seen = Arc::new(Mutex::new(None));
+    let seen2 = seen.clone();
+    server_cx.set_client_hello_hook(move |hello: &tokio_tls::ClientHello| {
+        *seen2.lock().unwrap() = Some((hello.ja3(), hello.ja4()));
+        Ok(())
+    });
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(server_cx.accept(socket).await);
+        let fingerprints = (
+            socket.ja3().unwrap().to_owned(),
+            socket.ja4().unwrap().to_owned(),
+        );
+        t!(socket.shutdown().await);
+        fingerprints
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket = t!(client_cx.connect("localhost", socket).await);
+        assert_eq!(socket.ja3(), None);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+    };
+
+    let (fingerprints, ()) = join!(server, client);
+    assert_eq!(fingerprints.0.len(), 32);
+    assert!(fingerprints.1.starts_with("t1"));
+    assert_eq!(Some(fingerprints), *seen.lock().unwrap());
+}
+
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+#[tokio::test]
+async fn client_auth() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let keys = client_keys();
+    let ca = tokio_tls::Certificate::from_der(keys.ca_der.clone());
+    let (mut required, _) = contexts();
+    t!(required.set_client_auth(tokio_tls::ClientAuth::Required(vec![ca.clone()])));
+    let (mut optional, _) = contexts();
+    t!(optional.set_client_auth(tokio_tls::ClientAuth::Optional(vec![ca])));
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(required.accept(socket).await);
+        let cert = t!(socket.client_certificate()).unwrap();
+        assert_eq!(t!(cert.parse()).subject(), "CN=client");
+        t!(socket.write_all(b"hello").await);
+        t!(socket.shutdown().await);
+
+        // Clients without a certificate are rejected if one is required.
+        let socket = t!(incoming.next().await.unwrap());
+        assert!(required.accept(socket).await.is_err());
+
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(optional.accept(socket).await);
+        assert_eq!(t!(socket.client_certificate()), None);
+        t!(socket.write_all(b"hello").await);
+        t!(socket.shutdown().await);
+
+        // Certificates which are not issued by the CA are always rejected.
+        let socket = t!(incoming.next().await.unwrap());
+        assert!(optional.accept(socket).await.is_err());
+    };
+
+    let client = async move {
+        let server_keys = openssl_keys();
+        let identities = [
+            Some((&keys.cert_der[..], &keys.pkey_der[..])),
+            None,
+            None,
+            Some((&server_keys.cert_der[..], &server_keys.pkey_der[..])),
+        ];
+        let mut accepted = Vec::new();
+        for identity in identities.iter() {
+            let socket = t!(TcpStream::connect(&addr).await);
+            let connector = client_connector(*identity);
+            // With TLS 1.3 the client may complete its side of the handshake
+            // before the server rejects its certificate.
+            let mut data = Vec::new();
+            let res = match connector.connect("localhost", socket).await {
+                Ok(mut socket) => {
+                    assert_eq!(t!(socket.client_certificate()), None);
+                    socket.read_to_end(&mut data).await.is_ok()
+                }
+                Err(_) => false,
+            };
+            accepted.push(res && data == b"hello");
+        }
+        accepted
+    };
+
+    let ((), accepted) = join!(server, client);
+    assert_eq!(accepted, [true, false, true, false]);
+}
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+#[tokio::test]
+async fn client_auth_unsupported() {
+    let (mut server_cx, _) = contexts();
+    t!(server_cx.set_client_auth(tokio_tls::ClientAuth::None));
+    let auth = tokio_tls::ClientAuth::Required(Vec::new());
+    assert!(server_cx.set_client_auth(auth).is_err());
+}
+
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+#[tokio::test]
+async fn certificate_verifier() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, _) = contexts();
+
+    // The connectors trust no roots, so the backend alone would reject the
+    // server's certificate.
+    cfg_if! {
+        if #[cfg(feature = "rustls")] {
+            let connector: tokio_tls::TlsConnector = Arc::new(ClientConfig::new()).into();
+        } else {
+            let connector: tokio_tls::TlsConnector = t!(SslConnector::builder(SslMethod::tls()))
+                .build()
+                .into();
+        }
+    }
+
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let pinned_seen = seen.clone();
+    let pin = move |chain: &[tokio_tls::Certificate], name: &str| {
+        let mut seen = pinned_seen.lock().unwrap();
+        seen.push((chain.len(), name.to_owned()));
+        if chain[0].der() == &openssl_keys().cert_der[..] {
+            Ok(())
+        } else {
+            Err("not pinned".to_owned())
+        }
+    };
+    let mut pinned = connector.clone();
+    t!(pinned.set_certificate_verifier(pin));
+    let reject = |_: &[tokio_tls::Certificate], _: &str| Err("not pinned".to_owned());
+    let mut rejecting = connector;
+    t!(rejecting.set_certificate_verifier(reject));
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(server_cx.accept(socket).await);
+        t!(socket.write_all(b"hello").await);
+        t!(socket.shutdown().await);
+
+        // The server's side of the rejected handshake is not checked.
+        let socket = t!(incoming.next().await.unwrap());
+        drop(server_cx.accept(socket).await);
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket = t!(pinned.connect("localhost", socket).await);
+        let mut data = Vec::new();
+        t!(socket.read_to_end(&mut data).await);
+        assert_eq!(data, b"hello");
+
+        let socket = t!(TcpStream::connect(&addr).await);
+        match rejecting.connect("localhost", socket).await {
+            Ok(_) => panic!("connection with a rejected certificate succeeded"),
+            Err(e) => e,
+        }
+    };
+
+    let ((), err) = join!(server, client);
+    assert_eq!(err.kind(), tokio_tls::ErrorKind::InvalidCertificate);
+    assert!(err.to_string().contains("not pinned"), "{}", err);
+    assert_eq!(*seen.lock().unwrap(), [(1, "localhost".to_owned())]);
+}
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+#[tokio::test]
+async fn certificate_verifier_unsupported() {
+    let (_, mut client_cx) = contexts();
+    let verifier = |_: &[tokio_tls::Certificate], _: &str| Ok(());
+    assert!(client_cx.set_certificate_verifier(verifier).is_err());
+}
+
+#[tokio::test]
+async fn pins() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    // Pin the server's key along with a backup key, which is the client CA's
+    // here, and then the backup key alone.
+    let dir = t!(env::current_exe());
+    let dir = dir.parent().unwrap();
+    let keys = openssl_keys();
+    client_keys();
+    let server_pin = openssl_pin(&dir.join("test.crt"));
+    let backup_pin = openssl_pin(&dir.join("client-ca.crt"));
+
+    let mut pins = tokio_tls::PinSet::new();
+    t!(pins.insert_base64(&server_pin));
+    t!(pins.insert_base64(&format!("sha256/{}", backup_pin)));
+    assert!(pins.insert_base64("c2hvcnQ=").is_err());
+    // The key pinned through the certificate is the one pinned above.
+    let server_cert = tokio_tls::Certificate::from_der(keys.cert_der.clone());
+    t!(pins.insert_certificate(&server_cert));
+    assert_eq!(pins.len(), 2);
+    let mut pinned = client_cx.clone();
+    pinned.set_pins(Some(pins));
+
+    let mut backup_only = tokio_tls::PinSet::new();
+    t!(backup_only.insert_base64(&backup_pin));
+    let mut mismatched = client_cx.clone();
+    mismatched.set_pins(Some(backup_only.clone()));
+    backup_only.set_report_only(true);
+    let mut reporting = client_cx;
+    reporting.set_pins(Some(backup_only));
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        for _ in 0..3 {
+            let socket = t!(incoming.next().await.unwrap());
+            if let Ok(mut socket) = server_cx.accept(socket).await {
+                drop(socket.write_all(b"hello").await);
+                drop(socket.shutdown().await);
+            }
+        }
+    };
+
+    let client = async move {
+        let mut results = Vec::new();
+        for connector in [pinned, mismatched, reporting].iter() {
+            let socket = t!(TcpStream::connect(&addr).await);
+            results.push(match connector.connect("localhost", socket).await {
+                Ok(mut socket) => {
+                    let mut data = Vec::new();
+                    t!(socket.read_to_end(&mut data).await);
+                    Ok(data)
+                }
+                Err(e) => Err(e),
+            });
+        }
+        results
+    };
+
+    let ((), results) = join!(server, client);
+    let mut results = results.into_iter();
+    assert_eq!(t!(results.next().unwrap()), b"hello");
+    let err = results.next().unwrap().unwrap_err();
+    assert_eq!(err.kind(), tokio_tls::ErrorKind::InvalidCertificate);
+    assert!(err.to_string().contains("pinned"), "{}", err);
+    assert_eq!(t!(results.next().unwrap()), b"hello");
+}
+
+/// Returns an acceptor which, unlike the ones of `contexts`, allows resuming
+/// sessions.
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+fn resuming_acceptor() -> tokio_tls::TlsAcceptor {
+    let keys = openssl_keys();
+    cfg_if! {
+        if #[cfg(feature = "rustls")] {
+            let cert = Certificate(keys.cert_der.clone());
+            let mut srv_config = ServerConfig::new(NoClientAuth::new());
+            t!(srv_config.set_single_cert(vec![cert], PrivateKey(keys.pkey_der.clone())));
+            Arc::new(srv_config).into()
+        } else {
+            let cert = t!(X509::from_der(&keys.cert_der));
+            let pkey = t!(PKey::private_key_from_der(&keys.pkey_der));
+            let mut srv_config = t!(SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()));
+            t!(srv_config.set_certificate(&cert));
+            t!(srv_config.set_private_key(&pkey));
+            srv_config.build().into()
+        }
+    }
+}
+
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+#[tokio::test]
+async fn session_resumption() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let server_cx = resuming_acceptor();
+    let mut client_cx = client_connector(None);
+    assert_eq!(client_cx.session_cache_size(), 0);
+    t!(client_cx.set_session_cache_size(8));
+    assert_eq!(client_cx.session_cache_size(), 8);
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        for _ in 0..4 {
+            let socket = t!(incoming.next().await.unwrap());
+            let mut socket = t!(server_cx.accept(socket).await);
+            t!(socket.write_all(b"hello").await);
+            t!(socket.shutdown().await);
+        }
+    };
+
+    // Sessions are cached separately for the domain alone and with the
+    // address, so each is only resumed the second time. The client reads to
+    // the end of each stream, which processes the sessions sent by the server
+    // after the handshake.
+    let stats = client_cx.clone();
+    let client = async move {
+        let mut resumed = Vec::new();
+        for &with_addr in [false, false, true, true].iter() {
+            let socket = t!(TcpStream::connect(&addr).await);
+            let mut socket = if with_addr {
+                t!(client_cx.connect_with_addr("localhost", addr, socket).await)
+            } else {
+                t!(client_cx.connect("localhost", socket).await)
+            };
+            let mut data = Vec::new();
+            t!(socket.read_to_end(&mut data).await);
+            assert_eq!(data, b"hello");
+            resumed.push(socket.session_resumed());
+        }
+        resumed
+    };
+
+    let ((), resumed) = join!(server, client);
+    let stats = stats.session_cache_stats();
+    assert_eq!((stats.hits(), stats.misses()), (2, 2));
+    assert_eq!(stats.hit_rate(), 0.5);
+    assert_eq!(resumed, [false, true, false, true]);
+    assert_eq!(stats.resumed(), 2);
+}
+
+#[cfg(any(feature = "openssl", feature = "rustls"))]
+#[tokio::test]
+async fn session_cache_verification() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let server_cx = resuming_acceptor();
+    let mut client_cx = client_connector(None);
+    t!(client_cx.set_session_cache_size(8));
+    let mut permissive_cx = client_cx.clone();
+    t!(permissive_cx.set_certificate_verifier(|_: &[tokio_tls::Certificate], _: &str| Ok(())));
+    assert_eq!(permissive_cx.session_cache_size(), 8);
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        for _ in 0..3 {
+            let socket = t!(incoming.next().await.unwrap());
+            let mut socket = t!(server_cx.accept(socket).await);
+            t!(socket.write_all(b"hello").await);
+            t!(socket.shutdown().await);
+        }
+    };
+
+    // The sessions established without verification are only resumed by
+    // the connector they were established with.
+    let stats = (client_cx.clone(), permissive_cx.clone());
+    let client = async move {
+        let mut resumed = Vec::new();
+        for cx in &[&permissive_cx, &permissive_cx, &client_cx] {
+            let socket = t!(TcpStream::connect(&addr).await);
+            let mut socket = t!(cx.connect("localhost", socket).await);
+            let mut data = Vec::new();
+            t!(socket.read_to_end(&mut data).await);
+            assert_eq!(data, b"hello");
+            resumed.push(socket.session_resumed());
+        }
+        resumed
+    };
+
+    let ((), resumed) = join!(server, client);
+    assert_eq!(resumed, [false, true, false]);
+    let (client_stats, permissive_stats) =
+        (stats.0.session_cache_stats(), stats.1.session_cache_stats());
+    assert_eq!((client_stats.hits(), client_stats.misses()), (0, 1));
+    assert_eq!((permissive_stats.hits(), permissive_stats.misses()), (1, 1));
+}
+
+#[cfg(not(any(feature = "openssl", feature = "rustls")))]
+#[tokio::test]
+async fn session_cache_unsupported() {
+    let (_, mut client_cx) = contexts();
+    t!(client_cx.set_session_cache_size(0));
+    assert!(client_cx.set_session_cache_size(8).is_err());
+    assert_eq!(
+        client_cx.session_cache_stats(),
+        tokio_tls::SessionCacheStats::default()
+    );
+}
+
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn server_session_resumption() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let acceptor = |resumption: tokio_tls::SessionResumption| {
+        let (mut server_cx, _) = contexts();
+        t!(server_cx.set_session_resumption(resumption));
+        server_cx
+    };
+    let cache = acceptor(tokio_tls::SessionResumption::Cache(8));
+    // Two servers of a fleet, sharing their ticket keys.
+    let keys = tokio_tls::TicketKeys::new([1; 32]);
+    let tickets = acceptor(tokio_tls::SessionResumption::Tickets(keys.clone()));
+    let other_tickets = acceptor(tokio_tls::SessionResumption::Tickets(keys.clone()));
+    let disabled = acceptor(tokio_tls::SessionResumption::Disabled);
+
+    let server = async move {
+        let acceptors = [
+            &cache,
+            &cache,
+            &tickets,
+            &other_tickets,
+            &tickets,
+            &disabled,
+        ];
+        let mut incoming = srv.incoming();
+        let mut resumed = Vec::new();
+        for (i, acceptor) in acceptors.iter().enumerate() {
+            // Tickets issued before a rotation stay valid.
+            if i == 4 {
+                keys.rotate([2; 32]);
+            }
+            let socket = t!(incoming.next().await.unwrap());
+            let mut socket = t!(acceptor.accept(socket).await);
+            resumed.push(socket.session_resumed());
+            t!(socket.write_all(b"hello").await);
+            t!(socket.shutdown().await);
+        }
+        resumed
+    };
+
+    // The client reads to the end of each stream to receive the tickets
+    // sent after the handshake.
+    let (_, client_cx) = contexts();
+    let client = async move {
+        for _ in 0..6 {
+            let socket = t!(TcpStream::connect(&addr).await);
+            let mut socket = t!(client_cx.connect("localhost", socket).await);
+            let mut data = Vec::new();
+            t!(socket.read_to_end(&mut data).await);
+            assert_eq!(data, b"hello");
+        }
+    };
+
+    let (resumed, ()) = join!(server, client);
+    assert_eq!(resumed, [false, true, false, true, true, false]);
+}
+
+#[cfg(not(feature = "rustls"))]
+#[tokio::test]
+async fn server_session_resumption_unsupported() {
+    let (mut server_cx, _) = contexts();
+    let resumption = tokio_tls::SessionResumption::Disabled;
+    assert!(server_cx.set_session_resumption(resumption).is_err());
+}
+
+#[cfg(all(feature = "openssl", not(feature = "rustls")))]
+#[tokio::test]
+async fn openssl_ssl() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let socket = t!(server_cx.accept(socket).await);
+        socket.ssl().map(|ssl| ssl.version_str())
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let socket = t!(client_cx.connect("localhost", socket).await);
+        socket.ssl().map(|ssl| ssl.version_str())
+    };
+
+    let (server_version, client_version) = join!(server, client);
+    assert!(server_version.is_some());
+    assert_eq!(server_version, client_version);
+}
+
+#[tokio::test]
+async fn listener() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    let server = async move {
+        let mut listener = tokio_tls::TlsListener::new(server_cx, srv.incoming());
+        listener.set_max_handshakes(2);
+
+        let mut accepted = 0;
+        let mut failed = 0;
+        while accepted + failed < 3 {
+            match listener.next().await.unwrap() {
+                Ok(mut socket) => {
+                    t!(socket.write_all(b"hello").await);
+                    accepted += 1;
+                }
+                Err(_) => failed += 1,
+            }
+        }
+        (accepted, failed)
+    };
+
+    let client = async move {
+        // A client which does not speak TLS must not stop the listener.
+        let mut bad = t!(TcpStream::connect(&addr).await);
+        t!(bad.write_all(b"GET / HTTP/1.0\r\n\r\n").await);
+        drop(bad);
+
+        for _ in 0..2 {
+            let socket = t!(TcpStream::connect(&addr).await);
+            let mut socket = t!(client_cx.connect("localhost", socket).await);
+            let mut buf = [0; 5];
+            t!(socket.read_exact(&mut buf).await);
+            assert_eq!(&buf, b"hello");
+        }
+    };
+
+    let ((accepted, failed), ()) = join!(server, client);
+    assert_eq!(accepted, 2);
+    assert_eq!(failed, 1);
+}
+
+#[tokio::test]
+async fn sni() {
+    drop(env_logger::try_init());
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+    let mut names = tokio_tls::ServerNameMap::new();
+    names.insert("LocalHost", server_cx);
+    let server_cx = tokio_tls::TlsAcceptor::with_resolver(names);
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(server_cx.accept(socket).await);
+        assert_eq!(socket.server_name(), Some("localhost"));
+        t!(socket.write_all(b"hello").await);
+
+        // Names without an acceptor are rejected.
+        let socket = t!(incoming.next().await.unwrap());
+        assert!(server_cx.accept(socket).await.is_err());
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket = t!(client_cx.connect("localhost", socket).await);
+        let mut buf = [0; 5];
+        t!(socket.read_exact(&mut buf).await);
+        assert_eq!(&buf, b"hello");
+
+        let socket = t!(TcpStream::connect(&addr).await);
+        assert!(client_cx.connect("example.com", socket).await.is_err());
+    };
+
+    join!(server, client);
+}
+
+#[tokio::test]
+async fn reload() {
+    drop(env_logger::try_init());
+
+    let keys = openssl_keys();
+    let path = t!(env::current_exe()).parent().unwrap().join("reload.p12");
+    t!(t!(File::create(&path)).write_all(&keys.pkcs12_der));
+    let files = tokio_tls::IdentityFiles::Pkcs12 {
+        path: path.clone(),
+        password: "foobar".to_string(),
+    };
+
+    let mut srv = t!(TcpListener::bind("127.0.0.1:0").await);
+    let addr = t!(srv.local_addr());
+
+    let (_, client_cx) = contexts();
+    let (server_cx, handle) = t!(files.load()).reloadable();
+    let mut watch = handle.watch_identity(files, Duration::from_millis(10));
+
+    // Nothing is reloaded as long as the files don't change.
+    let res = tokio::time::timeout(Duration::from_millis(50), watch.next()).await;
+    assert!(res.is_err());
+
+    // Rewrite the archive with a friendly name, so that its size changes
+    // even where modification times are coarse.
+    let dir = path.parent().unwrap();
+    let pkcs12out = t!(Command::new("openssl")
+        .arg("pkcs12")
+        .arg("-export")
+        .arg("-nodes")
+        .arg("-name")
+        .arg("reloaded")
+        .arg("-inkey")
+        .arg(dir.join("test.key"))
+        .arg("-in")
+        .arg(dir.join("test.crt"))
+        .arg("-password")
+        .arg("pass:foobar")
+        .output());
+    assert!(pkcs12out.status.success());
+    assert_ne!(pkcs12out.stdout.len(), keys.pkcs12_der.len());
+    t!(t!(File::create(&path)).write_all(&pkcs12out.stdout));
+    t!(watch.next().await.unwrap());
+
+    let server = async move {
+        let mut incoming = srv.incoming();
+        let socket = t!(incoming.next().await.unwrap());
+        let mut socket = t!(server_cx.accept(socket).await);
+        t!(socket.write_all(b"hello").await);
+    };
+
+    let client = async move {
+        let socket = t!(TcpStream::connect(&addr).await);
+        let mut socket = t!(client_cx.connect("localhost", socket).await);
+        let mut buf = [0; 5];
+        t!(socket.read_exact(&mut buf).await);
+        assert_eq!(&buf, b"hello");
+    };
+
+    join!(server, client);
+
+    // The watch ends once the acceptor can no longer be used.
+    drop(handle);
+    assert!(watch.next().await.is_none());
+}